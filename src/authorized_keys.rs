@@ -0,0 +1,1048 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+use crate::error::{Error, Result};
+use crate::grant;
+use crate::provider::{self, TagScope, KEY_TYPES};
+
+/// First line of a managed block, followed by the block name (i.e. `gh:alice`)
+pub const BEGIN_MARKER: &str = "# BEGIN superkeyloader";
+/// Last line of a managed block, followed by the block name
+pub const END_MARKER: &str = "# END superkeyloader";
+
+///
+/// A single public key line of an `authorized_keys` file
+///
+/// Format: `[<OPTIONS>] <KEY_TYPE> <KEY_DATA> [<COMMENT>]`
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::authorized_keys::KeyLine;
+///
+/// let line = r#"command="echo hello world",no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 alice@laptop"#;
+/// let key = KeyLine::parse(line).unwrap();
+///
+/// assert_eq!(key.options.as_deref(), Some(r#"command="echo hello world",no-pty"#));
+/// assert_eq!(key.key_type, "ssh-ed25519");
+/// assert_eq!(key.key_data, "AAAAC3NzaC1lZDI1NTE5");
+/// assert_eq!(key.comment.as_deref(), Some("alice@laptop"));
+///
+/// assert!(KeyLine::parse("# just a comment").is_none());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyLine {
+    pub options: Option<String>,
+    pub key_type: String,
+    pub key_data: String,
+    pub comment: Option<String>,
+}
+
+impl KeyLine {
+    ///
+    /// Parse an `authorized_keys` line, return `None` for blank lines, comments and garbage
+    ///
+    pub fn parse(line: &str) -> Option<KeyLine> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let fields = split_fields(line);
+        // Options are the first field, but only if it isn't already the key type
+        let key_type_index = fields
+            .iter()
+            .take(2)
+            .position(|field| KEY_TYPES.contains(field))?;
+
+        let key_data = fields.get(key_type_index + 1)?;
+        let options = match key_type_index {
+            0 => None,
+            _ => Some(fields[0].to_string()),
+        };
+        let comment = match fields.get(key_type_index + 2..) {
+            Some(rest) if !rest.is_empty() => Some(rest.join(" ")),
+            _ => None,
+        };
+
+        Some(KeyLine {
+            options,
+            key_type: fields[key_type_index].to_string(),
+            key_data: key_data.to_string(),
+            comment,
+        })
+    }
+
+    ///
+    /// Two lines hold the same key if key type and key data are equal
+    /// (options and comments are ignored)
+    ///
+    pub fn same_key(&self, other: &KeyLine) -> bool {
+        self.key_type == other.key_type && self.key_data == other.key_data
+    }
+}
+
+///
+/// Split a line on whitespaces, except the ones inside double quotes (options values)
+///
+fn split_fields(line: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, character) in line.char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(begin) = start.take() {
+                    fields.push(&line[begin..index]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(index);
+    }
+    if let Some(begin) = start {
+        fields.push(&line[begin..]);
+    }
+
+    fields
+}
+
+///
+/// Where a key line of an `authorized_keys` file comes from
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// Managed block of an identity, contains the block name (i.e. `gh:alice`)
+    Block(String),
+    /// Origin tag outside of managed blocks (written by older versions), contains its source
+    /// (i.e. `GH`)
+    Tagged(String),
+    /// Hand-written key
+    Unmanaged,
+}
+
+impl Origin {
+    pub fn is_managed(&self) -> bool {
+        *self != Origin::Unmanaged
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Block(name) => write!(f, "{}", name),
+            Origin::Tagged(source) => write!(f, "from-{}", source),
+            Origin::Unmanaged => write!(f, "unmanaged"),
+        }
+    }
+}
+
+///
+/// Changes made to an `authorized_keys` file, as key lines
+///
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    /// Keys that weren't in the file
+    pub added: Vec<String>,
+    /// Keys already in the file (anywhere, with any options or comment)
+    pub present: Vec<String>,
+    /// Keys of a managed block rewritten with new options or comment
+    pub updated: Vec<String>,
+    /// Keys removed from the file
+    pub removed: Vec<String>,
+    /// Keys outside of blocks (written by old versions) moved into their identity block
+    pub moved: Vec<String>,
+    /// Keys outside of blocks left in place, their tag can't be tied to an identity
+    pub unattributed: Vec<String>,
+}
+
+impl Changes {
+    pub fn extend(&mut self, other: Changes) {
+        self.added.extend(other.added);
+        self.present.extend(other.present);
+        self.updated.extend(other.updated);
+        self.removed.extend(other.removed);
+        self.moved.extend(other.moved);
+        self.unattributed.extend(other.unattributed);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.updated.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+///
+/// Content of an `authorized_keys` file
+///
+/// Lines are kept exactly as they are, keys are parsed on demand.
+///
+/// Keys written by superkeyloader live in managed blocks, one per identity:
+///
+/// ```text
+/// # BEGIN superkeyloader gh:alice
+/// ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 from-GH-id-1
+/// # END superkeyloader gh:alice
+/// ```
+///
+/// Only the content of a block is ever rewritten, everything outside it is left as it is.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+///
+/// let mut authorized_keys = AuthorizedKeys::parse("no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me\n");
+///
+/// // Same key, different comment
+/// assert!(!authorized_keys.add("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 from-GH-id-1"));
+/// assert!(authorized_keys.add("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-2"));
+/// assert_eq!(authorized_keys.lines().len(), 2);
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    lines: Vec<String>,
+}
+
+impl AuthorizedKeys {
+    pub fn parse(content: &str) -> AuthorizedKeys {
+        AuthorizedKeys {
+            lines: content.lines().map(String::from).collect(),
+        }
+    }
+
+    ///
+    /// Read an `authorized_keys` file, a missing file is an empty one
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::Io` if the file exists but cannot be read.
+    ///
+    pub fn read(path: &Path) -> Result<AuthorizedKeys> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(AuthorizedKeys::parse(&content)),
+            Err(ref why) if why.kind() == ErrorKind::NotFound => Ok(AuthorizedKeys::default()),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    ///
+    /// Atomically write the file
+    ///
+    /// Content is written to a temporary file in the same directory, synced to disk, and then
+    /// renamed over `path`, so a failure never leaves a half-written file. A missing directory
+    /// is created with mode `0700` and a new file with mode `0600`, mode and ownership of an
+    /// existing file are preserved. Symlinks are followed.
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InsecurePermissions` if the file or its directory is writable by group or
+    /// others (like sshd `StrictModes`), and `Error::Io` on any I/O error.
+    ///
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let path = match fs::canonicalize(path) {
+            Ok(target) => target,
+            Err(ref why) if why.kind() == ErrorKind::NotFound => path.to_path_buf(),
+            Err(why) => return Err(why.into()),
+        };
+        let directory = match path.parent() {
+            Some(directory) if directory != Path::new("") => directory,
+            _ => Path::new("."),
+        };
+
+        if !directory.exists() {
+            debug!("Creating directory '{}'", directory.display());
+            create_private_dir(directory)?;
+        }
+        check_permissions(directory)?;
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => {
+                check_permissions(&path)?;
+                Some(metadata)
+            }
+            Err(ref why) if why.kind() == ErrorKind::NotFound => None,
+            Err(why) => return Err(why.into()),
+        };
+
+        // Temporary files are created with mode 0600
+        let mut temp_file = NamedTempFile::new_in(directory)?;
+        temp_file.write_all(self.to_string().as_bytes())?;
+        if let Some(metadata) = metadata {
+            copy_owner_and_mode(temp_file.as_file(), &metadata)?;
+        }
+        temp_file.as_file().sync_all()?;
+        temp_file.persist(&path).map_err(|why| why.error)?;
+
+        // Make the rename durable
+        #[cfg(unix)]
+        File::open(directory)?.sync_all()?;
+
+        Ok(())
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    ///
+    /// Lines range (markers included) of the `name` managed block
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::CorruptedBlock` if the block has a begin marker but no end marker.
+    ///
+    fn block_range(&self, name: &str) -> Result<Option<(usize, usize)>> {
+        let begin_line = format!("{} {}", BEGIN_MARKER, name);
+        let end_line = format!("{} {}", END_MARKER, name);
+
+        let begin = match self.lines.iter().position(|line| line.trim() == begin_line) {
+            Some(begin) => begin,
+            None => return Ok(None),
+        };
+        match self.lines[begin..]
+            .iter()
+            .position(|line| line.trim() == end_line)
+        {
+            Some(length) => Ok(Some((begin, begin + length))),
+            None => Err(Error::CorruptedBlock(name.to_string())),
+        }
+    }
+
+    ///
+    /// Managed block name of every line, `None` for lines outside of blocks (markers included)
+    ///
+    fn line_blocks(&self) -> Vec<Option<&str>> {
+        let mut blocks = Vec::with_capacity(self.lines.len());
+        let mut block: Option<&str> = None;
+
+        for line in &self.lines {
+            let line = line.trim();
+            match block {
+                None => {
+                    block = line
+                        .strip_prefix(BEGIN_MARKER)
+                        .filter(|name| name.starts_with(' '))
+                        .map(str::trim);
+                    blocks.push(block);
+                }
+                Some(name) => {
+                    blocks.push(block);
+                    if line == format!("{} {}", END_MARKER, name) {
+                        block = None;
+                    }
+                }
+            }
+        }
+
+        blocks
+    }
+
+    ///
+    /// `true` for every line that is inside a managed block (markers included)
+    ///
+    fn managed_lines(&self) -> Vec<bool> {
+        self.line_blocks().iter().map(Option::is_some).collect()
+    }
+
+    ///
+    /// Key lines with their origin, in file order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use superkeyloader_lib::authorized_keys::{AuthorizedKeys, Origin};
+    ///
+    /// let authorized_keys = AuthorizedKeys::parse(
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me@laptop\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-LP-id-bob\n\
+    ///      ## BEGIN superkeyloader gh:alice\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5BBBB from-GH-id-1\n\
+    ///      ## END superkeyloader gh:alice\n",
+    /// );
+    /// let origins: Vec<Origin> = authorized_keys
+    ///     .origins()
+    ///     .into_iter()
+    ///     .map(|(origin, _)| origin)
+    ///     .collect();
+    ///
+    /// assert_eq!(
+    ///     origins,
+    ///     vec![
+    ///         Origin::Unmanaged,
+    ///         Origin::Tagged("LP".to_string()),
+    ///         Origin::Block("gh:alice".to_string()),
+    ///     ]
+    /// );
+    /// ```
+    ///
+    pub fn origins(&self) -> Vec<(Origin, String)> {
+        self.lines
+            .iter()
+            .zip(self.line_blocks())
+            .filter(|(line, _)| KeyLine::parse(line).is_some())
+            .map(|(line, block)| {
+                let origin = match (block, provider::parse_tag(line)) {
+                    (Some(name), _) => Origin::Block(name.to_string()),
+                    (None, Some((source, _))) => Origin::Tagged(source.to_string()),
+                    (None, None) => Origin::Unmanaged,
+                };
+                (origin, line.clone())
+            })
+            .collect()
+    }
+
+    ///
+    /// Key lines of the `name` managed block (empty if there is no such block)
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::CorruptedBlock` if the block has a begin marker but no end marker.
+    ///
+    pub fn block(&self, name: &str) -> Result<Vec<String>> {
+        Ok(match self.block_range(name)? {
+            Some((begin, end)) => self.lines[begin + 1..end].to_vec(),
+            None => Vec::new(),
+        })
+    }
+
+    ///
+    /// Write `keys` into the `name` managed block, creating it at the end of the file if needed
+    ///
+    /// Keys already in the file (in this block, another block, or hand-written) aren't added
+    /// again, but keys of this block are rewritten if their line changed (i.e. new options).
+    /// Without `sync` the block only grows, with `sync` its old keys that aren't in `keys`
+    /// are removed (and an empty block is removed too).
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::CorruptedBlock` if the block has a begin marker but no end marker.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+    ///
+    /// let mut authorized_keys = AuthorizedKeys::parse("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me\n");
+    /// let keys = vec![
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 from-GH-id-1".to_string(),
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-2".to_string(),
+    /// ];
+    /// let changes = authorized_keys.update_block("gh:alice", &keys, false).unwrap();
+    ///
+    /// assert_eq!(changes.added.len(), 1);
+    /// assert_eq!(changes.present.len(), 1);
+    /// assert_eq!(
+    ///     authorized_keys.to_string(),
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me\n\
+    ///      ## BEGIN superkeyloader gh:alice\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-2\n\
+    ///      ## END superkeyloader gh:alice\n"
+    /// );
+    /// ```
+    ///
+    pub fn update_block(&mut self, name: &str, keys: &[String], sync: bool) -> Result<Changes> {
+        let range = self.block_range(name)?;
+        let old_block = self.block(name)?;
+
+        // Keys of the file, except the ones in this block
+        let outside: Vec<KeyLine> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| match range {
+                Some((begin, end)) => *index < begin || *index > end,
+                None => true,
+            })
+            .filter_map(|(_, line)| KeyLine::parse(line))
+            .collect();
+
+        let mut changes = Changes::default();
+        // With sync only what isn't a key (i.e. comments) is kept
+        let mut block: Vec<String> = old_block
+            .iter()
+            .filter(|line| !sync || KeyLine::parse(line).is_none())
+            .cloned()
+            .collect();
+
+        for line in keys {
+            let key = match KeyLine::parse(line) {
+                Some(key) => key,
+                None => continue,
+            };
+            let find_in = |lines: &[String]| {
+                lines.iter().position(|line| match KeyLine::parse(line) {
+                    Some(present) => present.same_key(&key),
+                    None => false,
+                })
+            };
+
+            // Keys of this block are rewritten if their options (or comment) changed
+            if let Some(index) = find_in(&block) {
+                if block[index] == *line {
+                    changes.present.push(line.clone());
+                } else {
+                    block[index] = line.clone();
+                    changes.updated.push(line.clone());
+                }
+            } else if outside.iter().any(|present| present.same_key(&key)) {
+                changes.present.push(line.clone());
+            } else {
+                match find_in(&old_block) {
+                    Some(index) if old_block[index] == *line => changes.present.push(line.clone()),
+                    Some(..) => changes.updated.push(line.clone()),
+                    None => changes.added.push(line.clone()),
+                }
+                block.push(line.clone());
+            }
+        }
+
+        for line in &old_block {
+            if let Some(old_key) = KeyLine::parse(line) {
+                let kept = block
+                    .iter()
+                    .filter_map(|line| KeyLine::parse(line))
+                    .any(|key| key.same_key(&old_key));
+                if !kept {
+                    changes.removed.push(line.clone());
+                }
+            }
+        }
+
+        let has_keys = block.iter().any(|line| KeyLine::parse(line).is_some());
+        let mut new_lines = Vec::new();
+        if has_keys || (range.is_some() && !sync) {
+            new_lines.push(format!("{} {}", BEGIN_MARKER, name));
+            new_lines.extend(block);
+            new_lines.push(format!("{} {}", END_MARKER, name));
+        }
+
+        match range {
+            Some((begin, end)) => {
+                self.lines.splice(begin..=end, new_lines);
+            }
+            None => self.lines.extend(new_lines),
+        }
+
+        Ok(changes)
+    }
+
+    pub fn keys(&self) -> Vec<KeyLine> {
+        self.lines
+            .iter()
+            .filter_map(|line| KeyLine::parse(line))
+            .collect()
+    }
+
+    ///
+    /// Check if the key of `line` is already present (options and comments are ignored)
+    ///
+    pub fn contains(&self, line: &str) -> bool {
+        match KeyLine::parse(line) {
+            Some(key) => self.keys().iter().any(|present| present.same_key(&key)),
+            None => false,
+        }
+    }
+
+    ///
+    /// Add `line` unless its key is already present, return `true` if it was added
+    ///
+    pub fn add(&mut self, line: &str) -> bool {
+        if self.contains(line) {
+            return false;
+        }
+        self.lines.push(line.to_string());
+        true
+    }
+
+    ///
+    /// Remove managed keys (tagged by a provider in `scopes`) that aren't in `upstream` anymore
+    ///
+    /// Only `attributable` scopes are used: a key is removed only if its tag ties it to a synced
+    /// identity (i.e. `from-LP-id-alice`), tags shared by every user of a provider (i.e.
+    /// `from-GH-id-1`) may belong to anyone else. Hand-written lines, keys of other providers and
+    /// keys in managed blocks (see `update_block`) are never touched. Return removed lines.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+    /// use superkeyloader_lib::provider::TagScope;
+    ///
+    /// let mut authorized_keys = AuthorizedKeys::parse(
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me@laptop\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-LP-id-alice\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5BBBB from-LP-id-bob\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5CCCC from-GH-id-3\n",
+    /// );
+    /// let scopes = vec![TagScope::new("LP", Some("alice")), TagScope::new("GH", None)];
+    /// let removed = authorized_keys.remove_stale(&scopes, &[]);
+    ///
+    /// assert_eq!(removed, vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-LP-id-alice"]);
+    /// assert_eq!(authorized_keys.lines().len(), 3);
+    /// ```
+    ///
+    pub fn remove_stale(&mut self, scopes: &[TagScope], upstream: &[String]) -> Vec<String> {
+        let upstream: Vec<KeyLine> = upstream
+            .iter()
+            .filter_map(|line| KeyLine::parse(line))
+            .collect();
+
+        let is_stale = |line: &String| match KeyLine::parse(line) {
+            Some(key) => {
+                scopes
+                    .iter()
+                    .any(|scope| scope.attributable && scope.matches(line))
+                    && !upstream.iter().any(|present| present.same_key(&key))
+            }
+            None => false,
+        };
+
+        let managed = self.managed_lines();
+        let (removed, kept): (Vec<_>, Vec<_>) = self
+            .lines
+            .drain(..)
+            .zip(managed)
+            .partition(|(line, managed)| !managed && is_stale(line));
+        self.lines = kept.into_iter().map(|(line, _)| line).collect();
+        removed.into_iter().map(|(line, _)| line).collect()
+    }
+
+    ///
+    /// Remove the keys tagged by a provider in `scope` outside of managed blocks that are one of
+    /// `keys` (only key type and data are compared), return removed lines
+    ///
+    /// Used to move keys written by old versions into the block of their identity.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+    /// use superkeyloader_lib::provider::TagScope;
+    ///
+    /// let mut authorized_keys = AuthorizedKeys::parse(
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-1\n\
+    ///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5BBBB from-GH-id-2\n",
+    /// );
+    /// let keys = vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-1".to_string()];
+    /// let taken = authorized_keys.take_tagged(&TagScope::new("GH", None), &keys);
+    ///
+    /// assert_eq!(taken, keys);
+    /// assert_eq!(authorized_keys.lines().len(), 1);
+    /// ```
+    ///
+    pub fn take_tagged(&mut self, scope: &TagScope, keys: &[String]) -> Vec<String> {
+        let keys: Vec<KeyLine> = keys
+            .iter()
+            .filter_map(|line| KeyLine::parse(line))
+            .collect();
+
+        let is_taken = |line: &String| match KeyLine::parse(line) {
+            Some(key) => scope.matches(line) && keys.iter().any(|wanted| wanted.same_key(&key)),
+            None => false,
+        };
+
+        let managed = self.managed_lines();
+        let (taken, kept): (Vec<_>, Vec<_>) = self
+            .lines
+            .drain(..)
+            .zip(managed)
+            .partition(|(line, managed)| !managed && is_taken(line));
+        self.lines = kept.into_iter().map(|(line, _)| line).collect();
+        taken.into_iter().map(|(line, _)| line).collect()
+    }
+    ///
+    /// Remove the `name` managed block (markers included), return its key lines
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::CorruptedBlock` if the block has a begin marker but no end marker.
+    ///
+    pub fn remove_block(&mut self, name: &str) -> Result<Vec<String>> {
+        Ok(match self.block_range(name)? {
+            Some((begin, end)) => self
+                .lines
+                .drain(begin..=end)
+                .filter(|line| KeyLine::parse(line).is_some())
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    ///
+    /// Keys tagged by a provider in `scope` outside of managed blocks (written by old versions)
+    ///
+    pub fn tagged(&self, scope: &TagScope) -> Vec<String> {
+        self.lines
+            .iter()
+            .zip(self.managed_lines())
+            .filter(|(line, managed)| {
+                !managed && KeyLine::parse(line).is_some() && scope.matches(line)
+            })
+            .map(|(line, _)| line.clone())
+            .collect()
+    }
+
+    ///
+    /// Remove keys whose grant expired at `now` (UNIX time), see `grant::is_expired`
+    ///
+    /// Only time-limited keys are touched, blocks left without keys are removed. Return removed
+    /// lines.
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::CorruptedBlock` if a managed block has no end marker.
+    ///
+    pub fn prune_expired(&mut self, now: u64) -> Result<Vec<String>> {
+        let has_keys = |lines: &[String]| lines.iter().any(|line| KeyLine::parse(line).is_some());
+
+        let mut blocks = Vec::new();
+        for name in self.block_names() {
+            if has_keys(&self.block(&name)?) {
+                blocks.push(name);
+            }
+        }
+
+        let (removed, kept): (Vec<String>, Vec<String>) = self
+            .lines
+            .drain(..)
+            .partition(|line| KeyLine::parse(line).is_some() && grant::is_expired(line, now));
+        self.lines = kept;
+
+        for name in blocks {
+            if let Some((begin, end)) = self.block_range(&name)? {
+                if !has_keys(&self.lines[begin + 1..end]) {
+                    self.lines.drain(begin..=end);
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    ///
+    /// Names of the managed blocks, in file order
+    ///
+    fn block_names(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .filter_map(|line| {
+                line.trim()
+                    .strip_prefix(BEGIN_MARKER)
+                    .filter(|name| name.starts_with(' '))
+                    .map(|name| name.trim().to_string())
+            })
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(directory: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    Ok(fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)?)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(directory: &Path) -> Result<()> {
+    Ok(fs::create_dir_all(directory)?)
+}
+
+///
+/// Refuse group or world writable files and directories, like sshd `StrictModes` does
+///
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o022 != 0 {
+        return Err(Error::InsecurePermissions(path.display().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_owner_and_mode(file: &File, metadata: &fs::Metadata) -> Result<()> {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    let current = file.metadata()?;
+    if current.uid() != metadata.uid() || current.gid() != metadata.gid() {
+        fchown(file, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    Ok(file.set_permissions(metadata.permissions())?)
+}
+
+#[cfg(not(unix))]
+fn copy_owner_and_mode(file: &File, metadata: &fs::Metadata) -> Result<()> {
+    Ok(file.set_permissions(metadata.permissions())?)
+}
+
+///
+/// File content, one line per entry (always terminated by a newline)
+///
+impl fmt::Display for AuthorizedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{split_fields, AuthorizedKeys, KeyLine};
+    use crate::error::Error;
+    use crate::provider::TagScope;
+
+    const ED25519_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg";
+
+    #[test]
+    fn fields_splitting() {
+        assert_eq!(split_fields("  a  b\tc "), vec!["a", "b", "c"]);
+        assert_eq!(
+            split_fields(r#"from="a b",command="echo \"x y\"" ssh-rsa AAAA"#),
+            vec![r#"from="a b",command="echo \"x y\"""#, "ssh-rsa", "AAAA"]
+        );
+    }
+
+    #[test]
+    fn key_line_parsing() {
+        let key = KeyLine::parse(ED25519_KEY).unwrap();
+        assert_eq!(key.options, None);
+        assert_eq!(key.comment, None);
+
+        let key = KeyLine::parse(&format!("{} from-GH-id-1 extra words", ED25519_KEY)).unwrap();
+        assert_eq!(key.comment.as_deref(), Some("from-GH-id-1 extra words"));
+
+        assert!(KeyLine::parse("").is_none());
+        assert!(KeyLine::parse("ssh-ed25519").is_none());
+        assert!(KeyLine::parse("helloooo").is_none());
+        assert!(KeyLine::parse("a b ssh-ed25519 AAAA").is_none());
+    }
+
+    #[test]
+    fn duplicated_keys() {
+        let content = format!("# My keys\n\nrestrict {} old comment\n", ED25519_KEY);
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+
+        assert!(authorized_keys.contains(ED25519_KEY));
+        assert!(!authorized_keys.add(&format!("{} from-GH-id-1", ED25519_KEY)));
+        assert_eq!(authorized_keys.lines().len(), 3);
+    }
+
+    #[test]
+    fn stale_keys() {
+        let content = format!(
+            "{key} from-GH-id-1\n{key}AAAA from-LP-id-alice\n{key}BBBB from-LP-id-bob\n",
+            key = ED25519_KEY
+        );
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+
+        // Same key still upstream, but with a new id
+        let upstream = vec![format!("{} from-LP-id-alice", ED25519_KEY)];
+        let scopes = vec![
+            TagScope::new("GH", None),
+            TagScope::new("LP", Some("alice")),
+        ];
+
+        // GitHub tags can't be tied to alice, they are left alone
+        assert_eq!(authorized_keys.remove_stale(&scopes, &upstream).len(), 1);
+        assert_eq!(authorized_keys.lines().len(), 2);
+        assert!(authorized_keys.lines()[0].ends_with("from-GH-id-1"));
+        assert!(authorized_keys.lines()[1].ends_with("from-LP-id-bob"));
+    }
+
+    #[test]
+    fn managed_blocks() {
+        let content = format!(
+            "# BEGIN superkeyloader gh:alice\n{key} from-GH-id-1\n# END superkeyloader gh:alice\n\
+             {key}AAAA from-GH-id-2\n",
+            key = ED25519_KEY
+        );
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+
+        assert_eq!(authorized_keys.block("gh:alice").unwrap().len(), 1);
+        assert!(authorized_keys.block("gh:bob").unwrap().is_empty());
+        assert_eq!(
+            authorized_keys.managed_lines(),
+            vec![true, true, true, false]
+        );
+
+        // Keys in blocks aren't removed by tag
+        let scopes = vec![TagScope::single_identity("GH")];
+        assert_eq!(authorized_keys.remove_stale(&scopes, &[]).len(), 1);
+        assert_eq!(authorized_keys.lines().len(), 3);
+
+        // Without sync the block only grows
+        let keys = vec![format!("{}BBBB from-GH-id-3", ED25519_KEY)];
+        let changes = authorized_keys
+            .update_block("gh:alice", &keys, false)
+            .unwrap();
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(authorized_keys.block("gh:alice").unwrap().len(), 2);
+
+        // With sync the block is replaced
+        let changes = authorized_keys
+            .update_block("gh:alice", &keys, true)
+            .unwrap();
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.present.len(), 1);
+        assert_eq!(changes.removed.len(), 1);
+        assert_eq!(authorized_keys.block("gh:alice").unwrap(), keys);
+
+        // New options rewrite the key, even without sync
+        let keys = vec![format!("restrict {}BBBB from-GH-id-3", ED25519_KEY)];
+        let changes = authorized_keys
+            .update_block("gh:alice", &keys, false)
+            .unwrap();
+        assert!(changes.present.is_empty());
+        assert_eq!(changes.updated, keys);
+        assert_eq!(authorized_keys.block("gh:alice").unwrap(), keys);
+
+        // Empty blocks are removed
+        authorized_keys.update_block("gh:alice", &[], true).unwrap();
+        assert!(authorized_keys.lines().is_empty());
+    }
+
+    #[test]
+    fn expired_grants() {
+        let content = format!(
+            "# BEGIN superkeyloader gh:alice\n\
+             {key} grant-until-20200401T000000Z from-GH-id-1\n\
+             # END superkeyloader gh:alice\n\
+             # BEGIN superkeyloader gh:bob\n\
+             {key}AAAA grant-until-20200401T000000Z from-GH-id-2\n\
+             {key}BBBB grant-until-20300401T000000Z from-GH-id-3\n\
+             # END superkeyloader gh:bob\n\
+             {key}CCCC me@laptop\n",
+            key = ED25519_KEY
+        );
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+
+        assert!(authorized_keys
+            .prune_expired(1585699200)
+            .unwrap()
+            .is_empty());
+        let removed = authorized_keys.prune_expired(1600000000).unwrap();
+        assert_eq!(removed.len(), 2);
+        assert!(authorized_keys.block("gh:alice").unwrap().is_empty());
+        assert_eq!(authorized_keys.block("gh:bob").unwrap().len(), 1);
+        assert_eq!(authorized_keys.lines().len(), 4);
+    }
+
+    #[test]
+    fn revoked_keys() {
+        let content = format!(
+            "# BEGIN superkeyloader lp:alice\n{key} from-LP-id-alice\n# END superkeyloader lp:alice\n\
+             {key}AAAA from-LP-id-alice\n{key}BBBB from-LP-id-bob\n",
+            key = ED25519_KEY
+        );
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+        let scope = TagScope::new("LP", Some("alice"));
+
+        assert_eq!(authorized_keys.tagged(&scope).len(), 1);
+        assert_eq!(authorized_keys.remove_block("lp:alice").unwrap().len(), 1);
+        assert!(authorized_keys.remove_block("lp:alice").unwrap().is_empty());
+        assert_eq!(authorized_keys.lines().len(), 2);
+        assert_eq!(authorized_keys.tagged(&scope).len(), 1);
+    }
+
+    #[test]
+    fn corrupted_block() {
+        let content = format!("# BEGIN superkeyloader gh:alice\n{}\n", ED25519_KEY);
+        let mut authorized_keys = AuthorizedKeys::parse(&content);
+
+        assert!(matches!(
+            authorized_keys.update_block("gh:alice", &[], false),
+            Err(Error::CorruptedBlock(_))
+        ));
+        // Unterminated blocks are managed till the end of the file
+        assert_eq!(authorized_keys.managed_lines(), vec![true, true]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let home = tempfile::tempdir().unwrap();
+        let path = home.path().join(".ssh").join("authorized_keys");
+        let mode =
+            |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // Missing directory and file
+        let authorized_keys = AuthorizedKeys::parse(ED25519_KEY);
+        authorized_keys.write(&path).unwrap();
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", ED25519_KEY)
+        );
+
+        // Existing file mode is preserved, and no temporary file is left behind
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        AuthorizedKeys::parse("").write(&path).unwrap();
+        assert_eq!(mode(&path), 0o640);
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn insecure_permissions() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let home = tempfile::tempdir().unwrap();
+        let path = home.path().join("authorized_keys");
+        fs::write(&path, ED25519_KEY).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(matches!(
+            AuthorizedKeys::parse("").write(&path),
+            Err(Error::InsecurePermissions(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), ED25519_KEY);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(home.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(matches!(
+            AuthorizedKeys::parse("").write(&path),
+            Err(Error::InsecurePermissions(_))
+        ));
+    }
+
+    #[test]
+    fn display() {
+        let authorized_keys = AuthorizedKeys::parse(ED25519_KEY);
+
+        assert_eq!(authorized_keys.to_string(), format!("{}\n", ED25519_KEY));
+        assert_eq!(AuthorizedKeys::parse("").to_string(), "");
+    }
+}
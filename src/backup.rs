@@ -0,0 +1,284 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::authorized_keys::AuthorizedKeys;
+use crate::error::{Error, Result};
+use crate::time;
+
+const BACKUP_TAG: &str = ".superkeyloader-";
+const BACKUP_EXTENSION: &str = ".bak";
+/// Extension of the backups made by `restore`, never picked as the latest backup
+const RESTORE_EXTENSION: &str = ".pre-restore.bak";
+
+///
+/// Backup name prefix for `path` (i.e. `authorized_keys.superkeyloader-`)
+///
+fn backup_prefix(path: &Path) -> String {
+    let file_name = path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    format!("{}{}", file_name, BACKUP_TAG)
+}
+
+///
+/// Timestamp and counter of a backup name, used to sort backups
+///
+fn backup_order(prefix: &str, name: &str) -> Option<(String, u32)> {
+    let stamp = name.strip_prefix(prefix)?;
+    let stamp = stamp
+        .strip_suffix(RESTORE_EXTENSION)
+        .or_else(|| stamp.strip_suffix(BACKUP_EXTENSION))?;
+    let mut parts = stamp.splitn(2, '-');
+    let timestamp = parts.next()?.to_string();
+    let counter = match parts.next() {
+        Some(counter) => counter.parse().ok()?,
+        None => 0,
+    };
+    Some((timestamp, counter))
+}
+
+///
+/// Check if `backup` was made by `restore` (the file as it was before being restored)
+///
+pub fn is_pre_restore(backup: &Path) -> bool {
+    backup.to_string_lossy().ends_with(RESTORE_EXTENSION)
+}
+
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(directory) if directory != Path::new("") => directory,
+        _ => Path::new("."),
+    }
+}
+
+///
+/// List the backups of `path`, newest first
+///
+/// Backups live next to the file: `<FILE_NAME>.superkeyloader-<TIMESTAMP>.bak`, or
+/// `<FILE_NAME>.superkeyloader-<TIMESTAMP>.pre-restore.bak` for the ones made by `restore`
+///
+/// # Errors
+///
+/// Return `Error::Io` if the directory cannot be read.
+///
+pub fn list(path: &Path) -> Result<Vec<PathBuf>> {
+    let prefix = backup_prefix(path);
+    let entries = match fs::read_dir(directory_of(path)) {
+        Ok(entries) => entries,
+        Err(ref why) if why.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(why) => return Err(why.into()),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(order) = backup_order(&prefix, &name) {
+            backups.push((order, entry.path()));
+        }
+    }
+    backups.sort();
+
+    Ok(backups.into_iter().rev().map(|(_, path)| path).collect())
+}
+
+///
+/// Copy `path` to a new timestamped backup, then delete the backups exceeding `retention`
+///
+/// Nothing is done if `retention` is `0` or `path` doesn't exist. Return the backup path.
+///
+/// # Errors
+///
+/// Return `Error::Io` if the backup cannot be written (old backups are deleted on a best effort
+/// basis).
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::backup;
+///
+/// let home = tempfile::tempdir().unwrap();
+/// let path = home.path().join("authorized_keys");
+/// std::fs::write(&path, "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5\n").unwrap();
+///
+/// let backup = backup::create(&path, 2).unwrap().unwrap();
+/// backup::create(&path, 2).unwrap();
+/// backup::create(&path, 2).unwrap();
+///
+/// assert_eq!(backup::list(&path).unwrap().len(), 2);
+/// assert!(!backup.exists());
+/// ```
+///
+pub fn create(path: &Path, retention: usize) -> Result<Option<PathBuf>> {
+    create_with_extension(path, retention, BACKUP_EXTENSION)
+}
+
+fn create_with_extension(
+    path: &Path,
+    retention: usize,
+    extension: &str,
+) -> Result<Option<PathBuf>> {
+    if retention == 0 || !path.exists() {
+        return Ok(None);
+    }
+
+    let stem = format!(
+        "{}{}",
+        backup_prefix(path),
+        time::format_timestamp(time::now())
+    );
+
+    let directory = directory_of(path);
+    let mut backup = directory.join(format!("{}{}", stem, extension));
+    let mut counter = 0;
+    while backup.exists() {
+        counter += 1;
+        backup = directory.join(format!("{}-{}{}", stem, counter, extension));
+    }
+
+    // Permissions are copied too
+    fs::copy(path, &backup)?;
+    info!(
+        "Backup of '{}' saved as '{}'",
+        path.display(),
+        backup.display()
+    );
+
+    for old_backup in list(path)?.iter().skip(retention) {
+        debug!("Deleting old backup '{}'", old_backup.display());
+        if let Err(why) = fs::remove_file(old_backup) {
+            warn!("Could not delete '{}': {}", old_backup.display(), why);
+        }
+    }
+
+    Ok(Some(backup))
+}
+
+///
+/// Find a backup of `path` by file name (or full path), or the latest one if `name` is `None`
+///
+/// The latest backup is never one made by `restore`, otherwise restoring twice would undo the
+/// first restore.
+///
+/// # Errors
+///
+/// Return `Error::BackupNotFound` if there is no such backup.
+///
+pub fn find(path: &Path, name: Option<&str>) -> Result<PathBuf> {
+    let backups = list(path)?;
+    let found = match name {
+        Some(name) => backups
+            .into_iter()
+            .find(|backup| backup.file_name() == Path::new(name).file_name()),
+        None => backups.into_iter().find(|backup| !is_pre_restore(backup)),
+    };
+    found.ok_or_else(|| Error::BackupNotFound(name.unwrap_or("latest").to_string()))
+}
+
+///
+/// Replace `path` with the content of `backup` (see `AuthorizedKeys::write`)
+///
+/// The current file is backed up first (`.pre-restore.bak`), so a restore can be undone by
+/// restoring that backup by name.
+///
+/// # Errors
+///
+/// Return `Error::Io` if files cannot be read or written, and `Error::InsecurePermissions` like
+/// `AuthorizedKeys::write`.
+///
+pub fn restore(path: &Path, backup: &Path, retention: usize) -> Result<()> {
+    let authorized_keys = AuthorizedKeys::parse(&fs::read_to_string(backup)?);
+    create_with_extension(path, retention, RESTORE_EXTENSION)?;
+    authorized_keys.write(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{backup_order, find, is_pre_restore, list, restore};
+    use crate::error::Error;
+    use std::fs;
+
+    #[test]
+    fn backup_names() {
+        let prefix = "authorized_keys.superkeyloader-";
+
+        assert_eq!(
+            backup_order(
+                prefix,
+                "authorized_keys.superkeyloader-20200401T000000Z-2.bak"
+            ),
+            Some(("20200401T000000Z".to_string(), 2))
+        );
+        assert_eq!(
+            backup_order(
+                prefix,
+                "authorized_keys.superkeyloader-20200401T000000Z.pre-restore.bak"
+            ),
+            Some(("20200401T000000Z".to_string(), 0))
+        );
+        assert!(backup_order(prefix, "authorized_keys").is_none());
+        assert!(backup_order(prefix, "known_hosts.superkeyloader-20200401T000000Z.bak").is_none());
+    }
+
+    #[test]
+    fn list_and_restore() {
+        let home = tempfile::tempdir().unwrap();
+        let path = home.path().join("authorized_keys");
+
+        // Missing directory has no backups
+        assert!(list(&home.path().join("missing").join("authorized_keys"))
+            .unwrap()
+            .is_empty());
+
+        for name in &[
+            "authorized_keys.superkeyloader-20200401T000000Z-1.bak",
+            "authorized_keys.superkeyloader-20200401T000000Z.bak",
+            "authorized_keys.superkeyloader-20200101T000000Z.bak",
+        ] {
+            fs::write(home.path().join(name), name).unwrap();
+        }
+        fs::write(&path, "current\n").unwrap();
+
+        let backups = list(&path).unwrap();
+        assert_eq!(backups.len(), 3);
+        assert!(backups[0].ends_with("authorized_keys.superkeyloader-20200401T000000Z-1.bak"));
+        assert!(backups[2].ends_with("authorized_keys.superkeyloader-20200101T000000Z.bak"));
+
+        let oldest = find(
+            &path,
+            Some("authorized_keys.superkeyloader-20200101T000000Z.bak"),
+        );
+        assert_eq!(oldest.unwrap(), backups[2]);
+        assert!(matches!(
+            find(
+                &path,
+                Some("authorized_keys.superkeyloader-19990101T000000Z.bak")
+            ),
+            Err(Error::BackupNotFound(_))
+        ));
+
+        restore(&path, &find(&path, None).unwrap(), 10).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "authorized_keys.superkeyloader-20200401T000000Z-1.bak\n"
+        );
+        // Current file was backed up before restoring
+        let backups = list(&path).unwrap();
+        assert_eq!(backups.len(), 4);
+        assert!(is_pre_restore(&backups[0]));
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "current\n");
+
+        // Restoring the latest backup again doesn't undo the first restore
+        restore(&path, &find(&path, None).unwrap(), 10).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "authorized_keys.superkeyloader-20200401T000000Z-1.bak\n"
+        );
+
+        // Undo by name
+        restore(&path, &backups[0], 10).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "current\n");
+    }
+}
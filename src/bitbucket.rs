@@ -0,0 +1,420 @@
+extern crate pretty_env_logger;
+
+use regex::Regex;
+
+use crate::error::{self, Error, Result};
+use crate::provider::{self, KeyProvider, TagScope};
+use crate::ssh_key::SshPublicKey;
+
+/// Bitbucket Cloud API base URL
+pub const DEFAULT_BASE_URL: &str = "https://api.bitbucket.org/2.0";
+
+/// Upper limit on followed `next` links, protects against a broken paginator
+const MAX_PAGES: usize = 100;
+
+///
+/// Bitbucket API key parsing struct (REST 2.0)
+///
+/// [Documentation](https://developer.atlassian.com/bitbucket/api/2/reference/resource/users/%7Bselected_user%7D/ssh-keys)
+///
+/// The key `uuid` comes wrapped in curly braces (i.e. `{b15b6026-...}`).
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BbKey {
+    pub uuid: String,
+    pub key: String,
+}
+
+///
+/// Bitbucket API paginated response
+///
+/// URL: `GET https://api.bitbucket.org/2.0/users/<USERNAME>/ssh-keys`
+///
+/// `next` is missing on the last page.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::bitbucket::BbKeysPage;
+///
+/// let json_string = r#"
+///   {
+///     "pagelen": 10,
+///     "values": [
+///       {
+///         "uuid": "{b15b6026-9c02-4626-b4ad-b905f99f763a}",
+///         "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHk",
+///         "label": "laptop"
+///       }
+///     ],
+///     "next": "https://api.bitbucket.org/2.0/users/alice/ssh-keys?page=2"
+///   }
+/// "#;
+/// let page: BbKeysPage = serde_json::from_str(&json_string).unwrap();
+///
+/// assert_eq!(page.values[0].uuid, "{b15b6026-9c02-4626-b4ad-b905f99f763a}");
+/// assert!(page.next.is_some());
+/// ```
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BbKeysPage {
+    pub values: Vec<BbKey>,
+    pub next: Option<String>,
+}
+
+///
+/// Validate Bitbucket usernames
+///
+/// # Rules
+///   - Max 30 characters, alphanumerical, '_' and '-'
+///   - OR an account UUID wrapped in curly braces (accepted by the API too)
+///
+fn validate_username(username: &str) -> bool {
+    let username_rule = Regex::new(r"^([-a-zA-Z\d_]{1,30}|\{[a-fA-F\d-]{36}\})$").unwrap();
+    username_rule.is_match(username)
+}
+
+///
+/// Bitbucket Cloud key provider
+///
+/// Output keys format is the following:
+/// `<SSH_KEY> from-BB-id-<KEY_UUID>`
+///
+/// > `KEY_UUID` is the Bitbucket key uuid, without curly braces.
+///
+/// Authentication uses an app password, `auth` must be in the `<USERNAME>:<APP_PASSWORD>` format
+/// (the username is the owner of the app password, not the requested user).
+///
+/// Credentials are sent only to `base_url`: `next` page links pointing anywhere else are refused.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::bitbucket::{BitbucketClient, DEFAULT_BASE_URL};
+/// use superkeyloader_lib::provider::KeyProvider;
+///
+/// let bitbucket = BitbucketClient::new(DEFAULT_BASE_URL, None);
+///
+/// assert_eq!(bitbucket.name(), "bitbucket");
+/// assert!(bitbucket.validate_identity("alice_smith"));
+/// assert!(!bitbucket.validate_identity("alice.smith"));
+/// ```
+///
+pub struct BitbucketClient {
+    base_url: String,
+    auth: Option<String>,
+}
+
+impl BitbucketClient {
+    pub fn new(base_url: &str, auth: Option<String>) -> BitbucketClient {
+        BitbucketClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth,
+        }
+    }
+
+    ///
+    /// Username and app password of `auth`, both are required
+    ///
+    fn credentials(&self) -> Result<Option<(&str, &str)>> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        let mut credentials = auth.splitn(2, ':');
+        match (credentials.next(), credentials.next()) {
+            (Some(user), Some(password)) if !user.is_empty() && !password.is_empty() => {
+                Ok(Some((user, password)))
+            }
+            _ => Err(Error::InvalidCredentials(
+                "Bitbucket app password must be in the '<USERNAME>:<APP_PASSWORD>' format"
+                    .to_string(),
+            )),
+        }
+    }
+
+    fn get_page(
+        &self,
+        url: &str,
+        identity: &str,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<BbKeysPage> {
+        debug!("Bitbucket API endpoint URL: {}", url);
+
+        let mut request = ureq::get(url);
+
+        if let Some((user, password)) = credentials {
+            request.auth(user, password);
+        }
+
+        let response = error::check_response(request.call(), identity)?;
+
+        let resp_json = error::read_body(response)?;
+        Ok(serde_json::from_str(&resp_json)?)
+    }
+}
+
+impl KeyProvider for BitbucketClient {
+    fn name(&self) -> &str {
+        "bitbucket"
+    }
+
+    fn display_name(&self) -> &str {
+        "Bitbucket"
+    }
+
+    fn validate_identity(&self, identity: &str) -> bool {
+        validate_username(identity)
+    }
+
+    fn tag_scope(&self, _identity: &str) -> TagScope {
+        TagScope::new("BB", None)
+    }
+
+    ///
+    /// Download user's SSH keys from Bitbucket, following all the `next` page links
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidUsername` if Bitbucket username isn't valid,
+    /// `Error::InvalidCredentials` if the app password has no username,
+    /// `Error::MalformedResponse` if Bitbucket API response could not be parsed, links a page
+    /// outside of `base_url` or has more than `MAX_PAGES` pages (a partial key list must never
+    /// look complete), and the errors of `error::check_response` for non 2XX responses.
+    ///
+    fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>> {
+        if !validate_username(identity) {
+            return Err(Error::InvalidUsername(identity.to_string()));
+        }
+        let credentials = self.credentials()?;
+
+        let mut keys = Vec::new();
+        let mut next_url = Some(format!("{}/users/{}/ssh-keys", self.base_url, identity));
+
+        for _ in 0..MAX_PAGES {
+            let url = match next_url {
+                Some(url) => url,
+                None => return Ok(keys),
+            };
+            // Credentials are never sent to another host
+            if !url.starts_with(&format!("{}/", self.base_url)) {
+                return Err(Error::MalformedResponse(format!(
+                    "next page link '{}' isn't on {}",
+                    url, self.base_url
+                )));
+            }
+            let page = self.get_page(&url, identity, credentials)?;
+
+            keys.extend(page.values.into_iter().filter_map(|key| {
+                let uuid = key.uuid.trim_start_matches('{').trim_end_matches('}');
+                provider::parse_key(&key.key, "BB", uuid)
+            }));
+            next_url = page.next;
+        }
+
+        match next_url {
+            Some(..) => Err(Error::MalformedResponse(format!(
+                "more than {} pages of keys",
+                MAX_PAGES
+            ))),
+            None => Ok(keys),
+        }
+    }
+}
+
+pub mod test_values {
+
+    pub const VALID_USERNAME: &str = "testuser";
+    pub const MISSING_USERNAME: &str = "erruser";
+    pub const INVALID_USERNAME: &str = "test.user";
+
+    pub const PAGE_1_JSON: &str = r#"{
+      "pagelen": 1,
+      "values": [
+        {
+          "uuid": "{b15b6026-9c02-4626-b4ad-b905f99f763a}",
+          "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf",
+          "label": "laptop"
+        }
+      ],
+      "next": "NEXT_PAGE_URL"
+    }"#;
+
+    pub const PAGE_2_JSON: &str = r#"{
+      "pagelen": 1,
+      "values": [
+        {
+          "uuid": "{0b7f7ac7-46d1-4b5b-8c4a-3bd1e2b7c8d9}",
+          "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak",
+          "label": "desktop"
+        }
+      ]
+    }"#;
+
+    pub const INVALID_JSON: &str = r#"[{"uuid": 1}]"#;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_values::*;
+    use super::BitbucketClient;
+    use crate::error::Error;
+    use crate::provider::KeyProvider;
+
+    use mockito::{mock, Matcher};
+
+    fn init() {
+        let _ = pretty_env_logger::env_logger::builder()
+            .is_test(true)
+            .try_init();
+    }
+
+    #[test]
+    fn test_bitbucket_username_validation() {
+        init();
+
+        assert!(super::validate_username(VALID_USERNAME));
+        assert!(super::validate_username(
+            "{b15b6026-9c02-4626-b4ad-b905f99f763a}"
+        ));
+        assert!(!super::validate_username(INVALID_USERNAME));
+        assert!(!super::validate_username(""));
+    }
+
+    #[test]
+    fn paginated_response() {
+        init();
+        let next_url = format!("{}/users/testuser/ssh-keys?page=2", mockito::server_url());
+        let _m1 = mock("GET", "/users/testuser/ssh-keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(PAGE_1_JSON.replace("NEXT_PAGE_URL", &next_url))
+            .create();
+        let _m2 = mock("GET", "/users/testuser/ssh-keys?page=2")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(PAGE_2_JSON)
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), None);
+        let keys = bitbucket.get_keys(VALID_USERNAME).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0].comment(),
+            Some("from-BB-id-b15b6026-9c02-4626-b4ad-b905f99f763a")
+        );
+        assert_eq!(
+            keys[1].comment(),
+            Some("from-BB-id-0b7f7ac7-46d1-4b5b-8c4a-3bd1e2b7c8d9")
+        );
+    }
+
+    #[test]
+    fn app_password() {
+        init();
+        // 'alice:secret' encoded in base64
+        let _m = mock("GET", "/users/testuser/ssh-keys")
+            .match_header(
+                "Authorization",
+                Matcher::Exact("Basic YWxpY2U6c2VjcmV0".into()),
+            )
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(PAGE_2_JSON)
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), Some("alice:secret".into()));
+
+        assert_eq!(bitbucket.get_keys(VALID_USERNAME).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn app_password_without_username() {
+        init();
+        let _m = mock("GET", "/users/testuser/ssh-keys")
+            .with_status(200)
+            .with_body(PAGE_2_JSON)
+            .expect(0)
+            .create();
+
+        for auth in &["secret", ":secret", "alice:"] {
+            let bitbucket = BitbucketClient::new(&mockito::server_url(), Some(auth.to_string()));
+
+            assert!(matches!(
+                bitbucket.get_keys(VALID_USERNAME),
+                Err(Error::InvalidCredentials(_))
+            ));
+        }
+        _m.assert();
+    }
+
+    #[test]
+    fn foreign_next_page() {
+        init();
+        let _m = mock("GET", "/users/testuser/ssh-keys")
+            .with_status(200)
+            .with_body(PAGE_1_JSON.replace(
+                "NEXT_PAGE_URL",
+                "https://collector.example/users/testuser/ssh-keys?page=2",
+            ))
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), Some("alice:secret".into()));
+
+        assert!(matches!(
+            bitbucket.get_keys(VALID_USERNAME),
+            Err(Error::MalformedResponse(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_pages() {
+        init();
+        let next_url = format!("{}/users/testuser/ssh-keys?page=2", mockito::server_url());
+        let _m = mock("GET", Matcher::Regex("^/users/testuser/ssh-keys".into()))
+            .with_status(200)
+            .with_body(PAGE_1_JSON.replace("NEXT_PAGE_URL", &next_url))
+            .expect(super::MAX_PAGES)
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), None);
+
+        assert!(matches!(
+            bitbucket.get_keys(VALID_USERNAME),
+            Err(Error::MalformedResponse(_))
+        ));
+        _m.assert();
+    }
+
+    #[test]
+    fn invalid_response() {
+        init();
+        let _m = mock("GET", "/users/testuser/ssh-keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(INVALID_JSON)
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), None);
+        let result = bitbucket.get_keys(VALID_USERNAME);
+
+        assert!(matches!(result, Err(Error::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn missing_username() {
+        init();
+        let _m = mock("GET", "/users/erruser/ssh-keys")
+            .with_status(404)
+            .with_header("Content-Type", "application/json")
+            .with_body(r#"{"type": "error"}"#)
+            .create();
+
+        let bitbucket = BitbucketClient::new(&mockito::server_url(), None);
+        let result = bitbucket.get_keys(MISSING_USERNAME);
+
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+}
@@ -0,0 +1,259 @@
+/// Unchanged lines printed around every change
+const CONTEXT_LINES: usize = 3;
+
+///
+/// A line of a diff between two files
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change<'a> {
+    Equal(&'a str),
+    Added(&'a str),
+    Removed(&'a str),
+}
+
+///
+/// Line by line diff of `old` and `new` (longest common subsequence)
+///
+/// The common prefix and suffix are skipped first, so the quadratic algorithm only runs on the
+/// changed part (usually a few lines of an `authorized_keys` file). Removed lines come before the
+/// added ones they are replaced with, like `diff -u`.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::diff::{diff_lines, Change};
+///
+/// let old = vec!["a".to_string(), "b".to_string()];
+/// let new = vec!["b".to_string(), "c".to_string()];
+///
+/// assert_eq!(
+///     diff_lines(&old, &new),
+///     vec![Change::Removed("a"), Change::Equal("b"), Change::Added("c")]
+/// );
+/// ```
+///
+pub fn diff_lines<'a>(old: &'a [String], new: &'a [String]) -> Vec<Change<'a>> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    let (old_changed, new_changed) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut changes = Vec::with_capacity(old.len().max(new.len()));
+    changes.extend(old[..prefix].iter().map(|line| Change::Equal(line)));
+    changes.extend(lcs_diff(old_changed, new_changed));
+    changes.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| Change::Equal(line)),
+    );
+
+    changes
+}
+
+///
+/// Longest common subsequence diff, quadratic in time and memory
+///
+fn lcs_diff<'a>(old: &'a [String], new: &'a [String]) -> Vec<Change<'a>> {
+    // lengths[i][j] = LCS length of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            changes.push(Change::Equal(&old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            changes.push(Change::Removed(&old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(&new[j]));
+            j += 1;
+        }
+    }
+
+    changes
+}
+
+///
+/// Hunk header range, the start alone if the range is a single line
+///
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+///
+/// Unified diff (like `diff -u`) of `old` and `new`, empty if they are equal
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::diff::unified_diff;
+///
+/// let old = vec!["a".to_string()];
+/// let new = vec!["a".to_string(), "b".to_string()];
+///
+/// assert_eq!(
+///     unified_diff(&old, &new, "old", "new"),
+///     "--- old\n+++ new\n@@ -1 +1,2 @@\n a\n+b\n"
+/// );
+/// assert!(unified_diff(&old, &old, "old", "new").is_empty());
+/// ```
+///
+pub fn unified_diff(old: &[String], new: &[String], old_name: &str, new_name: &str) -> String {
+    let changes = diff_lines(old, new);
+    let changed: Vec<usize> = changes
+        .iter()
+        .enumerate()
+        .filter(|(_, change)| !matches!(change, Change::Equal(_)))
+        .map(|(index, _)| index)
+        .collect();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    // Group changes closer than two contexts in the same hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for index in changed {
+        let begin = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + CONTEXT_LINES + 1).min(changes.len());
+        match hunks.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = end,
+            _ => hunks.push((begin, end)),
+        }
+    }
+
+    let mut output = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let (mut old_line, mut new_line, mut position) = (0, 0, 0);
+    for (begin, end) in hunks {
+        // Count lines before the hunk
+        for change in &changes[position..begin] {
+            match change {
+                Change::Equal(_) => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+                Change::Added(_) => new_line += 1,
+                Change::Removed(_) => old_line += 1,
+            }
+        }
+
+        let hunk = &changes[begin..end];
+        let old_count = hunk
+            .iter()
+            .filter(|change| !matches!(change, Change::Added(_)))
+            .count();
+        let new_count = hunk
+            .iter()
+            .filter(|change| !matches!(change, Change::Removed(_)))
+            .count();
+        output.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_line, old_count),
+            hunk_range(new_line, new_count)
+        ));
+        for change in hunk {
+            let line = match change {
+                Change::Equal(line) => format!(" {}\n", line),
+                Change::Added(line) => format!("+{}\n", line),
+                Change::Removed(line) => format!("-{}\n", line),
+            };
+            output.push_str(&line);
+        }
+
+        old_line += old_count;
+        new_line += new_count;
+        position = end;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{diff_lines, unified_diff, Change};
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn empty_files() {
+        let new = lines("a\nb");
+
+        assert!(diff_lines(&[], &[]).is_empty());
+        assert_eq!(
+            unified_diff(&[], &new, "old", "new"),
+            "--- old\n+++ new\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+        assert_eq!(
+            unified_diff(&new, &[], "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn changed_lines() {
+        assert_eq!(
+            diff_lines(&lines("a\nb\nc"), &lines("a\nx\nc")),
+            vec![
+                Change::Equal("a"),
+                Change::Removed("b"),
+                Change::Added("x"),
+                Change::Equal("c")
+            ]
+        );
+
+        // Only the changed middle is compared, with removals first
+        assert_eq!(
+            diff_lines(&lines("a\nb\nc\nd"), &lines("a\nx\ny\nd")),
+            vec![
+                Change::Equal("a"),
+                Change::Removed("b"),
+                Change::Removed("c"),
+                Change::Added("x"),
+                Change::Added("y"),
+                Change::Equal("d")
+            ]
+        );
+    }
+
+    #[test]
+    fn hunks() {
+        let old = lines("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12");
+        let new = lines("1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13");
+
+        assert_eq!(
+            unified_diff(&old, &new, "a", "b"),
+            "--- a\n+++ b\n\
+             @@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+             @@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+
+use crate::time;
+
+///
+/// Errors returned by providers and by the `authorized_keys` handling code
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::error::Error;
+///
+/// let error = Error::NotFound("alice".to_string());
+///
+/// match error {
+///     Error::NotFound(ref username) => assert_eq!(username, "alice"),
+///     _ => unreachable!(),
+/// }
+/// assert!(error.to_string().contains("Wrong username"));
+/// ```
+///
+#[derive(Debug)]
+pub enum Error {
+    /// Username (or identity) isn't allowed by the provider
+    InvalidUsername(String),
+    /// Provider doesn't know the user (HTTP 404)
+    NotFound(String),
+    /// API rate limit exceeded, `reset` is the UNIX time when the limit resets (if known)
+    RateLimited { reset: Option<u64> },
+    /// Provider rejected the credentials (HTTP 401 or 403)
+    AuthFailure(u16),
+    /// Credentials aren't in the format required by the provider, contains the reason
+    InvalidCredentials(String),
+    /// Any other non 2XX HTTP status code
+    UnexpectedStatus(u16),
+    /// Connection, DNS, TLS, etc. errors
+    Transport(String),
+    /// Provider response could not be parsed, contains the parser error
+    MalformedResponse(String),
+    /// Provider name (or alias) doesn't exist
+    UnknownProvider(String),
+    /// Keys URL isn't HTTPS, and plain HTTP isn't allowed
+    InsecureUrl(String),
+    /// User exists but has no SSH keys
+    NoKeys,
+    /// Text isn't a well-formed SSH public key, contains the reason
+    InvalidKey(String),
+    /// Key isn't allowed by the key policy, contains the reason
+    PolicyViolation(String),
+    /// `authorized_keys` options aren't valid, contains the reason
+    InvalidOptions(String),
+    /// Grant duration isn't valid (see `grant::parse_duration`)
+    InvalidDuration(String),
+    /// Managed block of `authorized_keys` file has a begin marker but no end marker
+    CorruptedBlock(String),
+    /// File or directory is writable by group or others (sshd `StrictModes` would ignore it)
+    InsecurePermissions(String),
+    /// There is no `authorized_keys` backup with this name
+    BackupNotFound(String),
+    /// Reading or writing local files failed
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidUsername(username) => write!(
+                f,
+                "Invalid username '{}'. Username isn't allowed by the provider. \
+                If you think this is an bug, please create a issue on at {}/issues",
+                username,
+                env!("CARGO_PKG_REPOSITORY")
+            ),
+            Error::NotFound(username) => write!(f, "Wrong username, '{}' doesn't exists", username),
+            Error::RateLimited { reset } => match reset.map(seconds_until) {
+                Some(seconds) if seconds > 0 => write!(
+                    f,
+                    "API rate limit exceeded, it resets in {} seconds (or use a token)",
+                    seconds
+                ),
+                _ => write!(f, "API rate limit exceeded, retry later (or use a token)"),
+            },
+            Error::AuthFailure(status) => write!(
+                f,
+                "Authentication failed (API response code: {}), check your token",
+                status
+            ),
+            Error::InvalidCredentials(why) => write!(f, "Invalid credentials: {}", why),
+            Error::UnexpectedStatus(status) => write!(f, "API response code: {}", status),
+            Error::Transport(why) => write!(f, "Connection error: {}", why),
+            Error::MalformedResponse(why) => write!(f, "Invalid provider API response: {}", why),
+            Error::UnknownProvider(name) => write!(f, "Unknown provider '{}'", name),
+            Error::InsecureUrl(url) => write!(
+                f,
+                "Refusing plain HTTP URL '{}', keys could be tampered with on the network \
+                (use HTTPS, or allow it with '--allow-http')",
+                url
+            ),
+            Error::NoKeys => write!(f, "User has no SSH keys available"),
+            Error::InvalidKey(why) => write!(f, "Invalid SSH public key: {}", why),
+            Error::PolicyViolation(why) => write!(f, "Key not allowed by policy, {}", why),
+            Error::InvalidOptions(why) => write!(f, "Invalid 'authorized_keys' options: {}", why),
+            Error::InvalidDuration(duration) => write!(
+                f,
+                "Invalid duration '{}', use a number followed by s, m, h, d or w (i.e. '8h')",
+                duration
+            ),
+            Error::CorruptedBlock(name) => write!(
+                f,
+                "Managed block '{}' of 'authorized_keys' file has no END marker, fix it by hand",
+                name
+            ),
+            Error::InsecurePermissions(path) => write!(
+                f,
+                "Refusing to write, '{}' is writable by group or others and sshd would ignore \
+                the keys (fix it with 'chmod go-w {}')",
+                path, path
+            ),
+            Error::BackupNotFound(name) => write!(
+                f,
+                "Backup '{}' not found (list backups with 'superkeyloader restore --list')",
+                name
+            ),
+            Error::Io(why) => write!(f, "I/O error: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(why: std::io::Error) -> Error {
+        Error::Io(why)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(why: serde_json::Error) -> Error {
+        Error::MalformedResponse(why.to_string())
+    }
+}
+
+fn seconds_until(timestamp: u64) -> i64 {
+    timestamp as i64 - time::now() as i64
+}
+
+///
+/// Convert a `ureq` response into an error if it isn't a 2XX response
+///
+/// Rate limits are detected both by `429` status code and by a `403` with no remaining requests
+/// (GitHub way), reset time is read from `X-RateLimit-Reset`/`RateLimit-Reset` (UNIX time) or
+/// from `Retry-After` (seconds).
+///
+pub(crate) fn check_response(response: ureq::Response, username: &str) -> Result<ureq::Response> {
+    if let Some(why) = response.synthetic_error() {
+        return Err(Error::Transport(why.to_string()));
+    }
+
+    if response.ok() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let remaining = response
+        .header("X-RateLimit-Remaining")
+        .or_else(|| response.header("RateLimit-Remaining"));
+
+    match status {
+        404 => Err(Error::NotFound(username.to_string())),
+        429 => Err(rate_limited(&response)),
+        403 if remaining == Some("0") => Err(rate_limited(&response)),
+        401 | 403 => Err(Error::AuthFailure(status)),
+        _ => Err(Error::UnexpectedStatus(status)),
+    }
+}
+
+fn rate_limited(response: &ureq::Response) -> Error {
+    let reset = response
+        .header("X-RateLimit-Reset")
+        .or_else(|| response.header("RateLimit-Reset"))
+        .and_then(|reset| reset.parse::<u64>().ok())
+        .or_else(|| {
+            response
+                .header("Retry-After")
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .map(|seconds| time::now() + seconds)
+        });
+    Error::RateLimited { reset }
+}
+
+///
+/// Read the whole response body as a string
+///
+pub(crate) fn read_body(response: ureq::Response) -> Result<String> {
+    response
+        .into_string()
+        .map_err(|why| Error::Transport(why.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{check_response, Error};
+
+    #[test]
+    fn status_codes() {
+        let not_found = ureq::Response::new(404, "Not Found", "");
+        let unauthorized = ureq::Response::new(401, "Unauthorized", "");
+        let server_error = ureq::Response::new(500, "Internal Server Error", "");
+
+        assert!(check_response(ureq::Response::new(200, "OK", "[]"), "alice").is_ok());
+        assert!(matches!(
+            check_response(not_found, "alice"),
+            Err(Error::NotFound(ref username)) if username == "alice"
+        ));
+        assert!(matches!(
+            check_response(unauthorized, "alice"),
+            Err(Error::AuthFailure(401))
+        ));
+        assert!(matches!(
+            check_response(server_error, "alice"),
+            Err(Error::UnexpectedStatus(500))
+        ));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let too_many = ureq::Response::new(429, "Too Many Requests", "");
+
+        assert!(matches!(
+            check_response(too_many, "alice"),
+            Err(Error::RateLimited { reset: None })
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+extern crate pretty_env_logger;
+
+use regex::{Regex, RegexSet};
+
+use crate::error::{self, Error, Result};
+use crate::provider::{self, KeyProvider, TagScope};
+use crate::ssh_key::SshPublicKey;
+
+/// Codeberg (the biggest public Forgejo instance), used when no other base URL is set
+pub const DEFAULT_BASE_URL: &str = "https://codeberg.org";
+
+/// Keys requested per page (Gitea default maximum is 50)
+const PAGE_LIMIT: usize = 50;
+
+/// Upper limit on requested pages, protects against a broken paginator
+const MAX_PAGES: usize = 100;
+
+///
+/// Gitea/Forgejo API response parsing struct (REST v1)
+///
+/// [Documentation](https://try.gitea.io/api/swagger#/user/userListKeys)
+///
+/// URL: `GET https://<HOST>/api/v1/users/<USERNAME>/keys?page=<N>&limit=<M>`
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::gitea::GtKey;
+///
+/// let json_string = r#"
+///   [
+///     {
+///       "id": 123,
+///       "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHk",
+///       "title": "laptop",
+///       "fingerprint": "SHA256:..."
+///     }
+///   ]
+/// "#;
+/// let keys: Vec<GtKey> = serde_json::from_str(&json_string).unwrap();
+///
+/// assert_eq!(keys[0].id, 123);
+/// ```
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GtKey {
+    pub id: u64,
+    pub key: String,
+}
+
+///
+/// Validate Gitea/Forgejo usernames
+///
+/// # Rules
+///   - Max 40 characters, alphanumerical, '_', '-' and '.'
+///   - Must start and end with an alphanumerical character
+///   - Cannot have consecutive special characters ('_', '-' or '.')
+///
+fn validate_username(username: &str) -> bool {
+    let username_rules =
+        RegexSet::new(vec![r"^[a-zA-Z\d][-a-zA-Z\d_.]{0,39}$", r".*[a-zA-Z\d]$"]).unwrap();
+    let forbidden = Regex::new(r"[-_.]{2}").unwrap();
+
+    let matches: Vec<_> = username_rules.matches(username).into_iter().collect();
+    // If all rules match and there are no forbidden patterns then the username is valid
+    username_rules.len() == matches.len() && !forbidden.is_match(username)
+}
+
+///
+/// Gitea, Forgejo and Codeberg key provider
+///
+/// Output keys format is the following:
+/// `<SSH_KEY> from-<HOST>-id-<KEY_ID>`
+///
+/// > `HOST` is the instance host (i.e. `codeberg.org`) with `-` escaped as `%2D`, `KEY_ID` the
+/// > instance key id, so keys are traceable to the instance they came from.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::gitea::GiteaClient;
+/// use superkeyloader_lib::provider::KeyProvider;
+///
+/// let forgejo = GiteaClient::new("https://git.example.com:3000/", None);
+///
+/// assert_eq!(forgejo.name(), "gitea");
+/// assert_eq!(forgejo.host(), "git.example.com:3000");
+/// assert!(forgejo.validate_identity("alice.smith"));
+/// assert!(!forgejo.validate_identity("alice-"));
+/// ```
+///
+pub struct GiteaClient {
+    base_url: String,
+    token: Option<String>,
+}
+
+impl GiteaClient {
+    pub fn new(base_url: &str, token: Option<String>) -> GiteaClient {
+        GiteaClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    /// Instance host (and port), without scheme and path
+    pub fn host(&self) -> &str {
+        let without_scheme = match self.base_url.find("://") {
+            Some(index) => &self.base_url[index + 3..],
+            None => &self.base_url,
+        };
+        without_scheme.split('/').next().unwrap_or(without_scheme)
+    }
+
+    ///
+    /// Tag source of the instance keys: the host with `-` escaped as `%2D`, so it never contains
+    /// the `-id-` separator of the tag (see `provider::parse_tag`)
+    ///
+    pub fn tag_source(&self) -> String {
+        self.host().replace('-', "%2D")
+    }
+
+    fn get_page(&self, identity: &str, page: usize) -> Result<Vec<GtKey>> {
+        let url = format!("{}/api/v1/users/{}/keys", self.base_url, identity);
+        debug!("Gitea API endpoint URL: {} (page {})", url, page);
+
+        let mut request = ureq::get(&url);
+        request
+            .query("page", &page.to_string())
+            .query("limit", &PAGE_LIMIT.to_string());
+
+        if let Some(token) = &self.token {
+            request.set("Authorization", format!("token {}", token).as_ref());
+        }
+
+        let response = error::check_response(request.call(), identity)?;
+
+        let resp_json = error::read_body(response)?;
+        Ok(serde_json::from_str(&resp_json)?)
+    }
+}
+
+impl KeyProvider for GiteaClient {
+    fn name(&self) -> &str {
+        "gitea"
+    }
+
+    fn display_name(&self) -> &str {
+        self.host()
+    }
+
+    fn validate_identity(&self, identity: &str) -> bool {
+        validate_username(identity)
+    }
+
+    fn tag_scope(&self, _identity: &str) -> TagScope {
+        TagScope::new(&self.tag_source(), None)
+    }
+
+    ///
+    /// Download user's SSH keys from a Gitea/Forgejo instance, requesting pages until a
+    /// non-full one is returned
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidUsername` if username isn't valid,
+    /// `Error::MalformedResponse` if API response could not be parsed or has more than `MAX_PAGES`
+    /// full pages (a partial key list must never look complete),
+    /// and the errors of `error::check_response` for non 2XX responses.
+    ///
+    fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>> {
+        if !validate_username(identity) {
+            return Err(Error::InvalidUsername(identity.to_string()));
+        }
+
+        let source = self.tag_source();
+        let mut keys = Vec::new();
+
+        // Gitea pages start from 1
+        for page in 1..=MAX_PAGES {
+            let gt_keys = self.get_page(identity, page)?;
+            let last_page = gt_keys.len() < PAGE_LIMIT;
+
+            keys.extend(
+                gt_keys
+                    .into_iter()
+                    .filter_map(|key| provider::parse_key(&key.key, &source, key.id)),
+            );
+
+            if last_page {
+                return Ok(keys);
+            }
+        }
+
+        Err(Error::MalformedResponse(format!(
+            "more than {} pages of keys",
+            MAX_PAGES
+        )))
+    }
+}
+
+pub mod test_values {
+
+    pub const VALID_USERNAME: &str = "test.user";
+    pub const MISSING_USERNAME: &str = "erruser";
+    pub const INVALID_USERNAME_START: &str = "_user";
+    pub const INVALID_USERNAME_ENDING_HYPHEN: &str = "user-";
+    pub const INVALID_USERNAME_CONSEC_SPECIAL: &str = "user-.user";
+
+    pub const VALID_2_KEYS_JSON: &str = r#"[
+      {
+        "id": 123,
+        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf",
+        "title": "laptop",
+        "fingerprint": "SHA256:aaaa"
+      },
+      {
+        "id": 124,
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak",
+        "title": "desktop",
+        "fingerprint": "SHA256:bbbb"
+      }
+    ]"#;
+
+    pub const INVALID_JSON: &str = r#"[{"id": "123"}]"#;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_values::*;
+    use super::GiteaClient;
+    use crate::error::Error;
+    use crate::provider::KeyProvider;
+    use crate::ssh_key::test_values::ED25519_KEY;
+
+    use mockito::{mock, Matcher};
+
+    fn init() {
+        let _ = pretty_env_logger::env_logger::builder()
+            .is_test(true)
+            .try_init();
+    }
+
+    fn page_matcher(page: &str) -> Matcher {
+        Matcher::AllOf(vec![
+            Matcher::UrlEncoded("page".into(), page.into()),
+            Matcher::UrlEncoded("limit".into(), super::PAGE_LIMIT.to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_gitea_username_validation() {
+        init();
+
+        assert!(super::validate_username(VALID_USERNAME));
+        assert!(!super::validate_username(INVALID_USERNAME_START));
+        assert!(!super::validate_username(INVALID_USERNAME_ENDING_HYPHEN));
+        assert!(!super::validate_username(INVALID_USERNAME_CONSEC_SPECIAL));
+    }
+
+    #[test]
+    fn instance_host() {
+        assert_eq!(
+            GiteaClient::new("https://codeberg.org", None).host(),
+            "codeberg.org"
+        );
+        assert_eq!(
+            GiteaClient::new("http://10.0.0.1:3000/git/", None).host(),
+            "10.0.0.1:3000"
+        );
+    }
+
+    #[test]
+    fn hyphenated_host_tag() {
+        let gitea = GiteaClient::new("https://git-id-server.example", None);
+        let line = format!("{} from-{}-id-42", ED25519_KEY, gitea.tag_source());
+
+        assert_eq!(gitea.tag_source(), "git%2Did%2Dserver.example");
+        assert_eq!(
+            crate::provider::parse_tag(&line),
+            Some(("git%2Did%2Dserver.example", "42"))
+        );
+        assert!(gitea.tag_scope("alice").matches(&line));
+    }
+
+    #[test]
+    fn valid_response() {
+        init();
+        let _m = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(page_matcher("1"))
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(VALID_2_KEYS_JSON)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), None);
+        let keys = gitea.get_keys(VALID_USERNAME).unwrap();
+        let expected_tag = format!("from-{}-id-123", gitea.host());
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].comment(), Some(expected_tag.as_str()));
+    }
+
+    #[test]
+    fn paginated_response() {
+        init();
+        let full_page: Vec<String> = (0..super::PAGE_LIMIT)
+            .map(|id| format!(r#"{{"id": {}, "key": "{}"}}"#, id + 1000, ED25519_KEY))
+            .collect();
+        let _m1 = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(page_matcher("1"))
+            .with_status(200)
+            .with_body(format!("[{}]", full_page.join(",")))
+            .create();
+        let _m2 = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(page_matcher("2"))
+            .with_status(200)
+            .with_body(VALID_2_KEYS_JSON)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), None);
+
+        assert_eq!(
+            gitea.get_keys(VALID_USERNAME).unwrap().len(),
+            super::PAGE_LIMIT + 2
+        );
+    }
+
+    #[test]
+    fn too_many_pages() {
+        init();
+        let full_page: Vec<String> = (0..super::PAGE_LIMIT)
+            .map(|id| format!(r#"{{"id": {}, "key": "{}"}}"#, id + 1000, ED25519_KEY))
+            .collect();
+        let _m = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body(format!("[{}]", full_page.join(",")))
+            .expect(super::MAX_PAGES)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), None);
+
+        assert!(matches!(
+            gitea.get_keys(VALID_USERNAME),
+            Err(Error::MalformedResponse(_))
+        ));
+        _m.assert();
+    }
+
+    #[test]
+    fn token_auth() {
+        init();
+        let _m = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(page_matcher("1"))
+            .match_header("Authorization", Matcher::Exact("token secret".into()))
+            .with_status(200)
+            .with_body(VALID_2_KEYS_JSON)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), Some("secret".into()));
+
+        assert_eq!(gitea.get_keys(VALID_USERNAME).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_response() {
+        init();
+        let _m = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(page_matcher("1"))
+            .with_status(200)
+            .with_body(INVALID_JSON)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), None);
+        let result = gitea.get_keys(VALID_USERNAME);
+
+        assert!(matches!(result, Err(Error::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn missing_username() {
+        init();
+        let _m = mock("GET", "/api/v1/users/erruser/keys")
+            .match_query(page_matcher("1"))
+            .with_status(404)
+            .with_body(r#"{"message": "user does not exist"}"#)
+            .create();
+
+        let gitea = GiteaClient::new(&mockito::server_url(), None);
+        let result = gitea.get_keys(MISSING_USERNAME);
+
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+}
@@ -2,8 +2,12 @@ extern crate pretty_env_logger;
 
 use regex::RegexSet;
 
-pub const INVALID_GH_USERNAME: u16 = 1001;
-pub const INVALID_GH_API_RESPONSE: u16 = 1002;
+use crate::error::{self, Error, Result};
+use crate::provider::{self, KeyProvider, TagScope};
+use crate::ssh_key::SshPublicKey;
+
+/// GitHub REST API base URL (GitHub Enterprise Server uses `https://<HOST>/api/v3`)
+pub const DEFAULT_BASE_URL: &str = "https://api.github.com";
 
 ///
 /// GitHub API response parsing struct (REST v3)
@@ -72,8 +76,34 @@ fn validate_username(username: &str) -> bool {
 ///
 /// Download user's SSH keys from GitHub
 ///
-/// Return a vector of `String` containing all the user keys in the exact same order they were send
-/// by the API.
+/// Shortcut for `GithubClient::new(DEFAULT_BASE_URL, token).get_keys(username)`, see
+/// `GithubClient` for output format and errors.
+///
+/// # Example
+///
+/// ```no_run
+/// let token: Option<String>;
+/// # token = std::env::var("GITHUB_TOKEN").ok();
+/// use superkeyloader_lib::github::get_keys;
+///
+/// let keys = get_keys("biosan", token).unwrap();
+///
+/// assert!(keys[0].algorithm().starts_with("ssh-"));
+/// assert!(keys[0].comment().unwrap().starts_with("from-GH-id-"));
+/// ```
+///
+pub fn get_keys(username: &str, token: Option<String>) -> Result<Vec<SshPublicKey>> {
+    GithubClient::new(DEFAULT_BASE_URL, token).get_keys(username)
+}
+
+///
+/// GitHub key provider
+///
+/// URL: `GET <BASE_URL>/users/<USERNAME>/keys`, where `BASE_URL` is `https://api.github.com` or
+/// the API URL of a GitHub Enterprise Server instance (i.e. `https://ghe.corp/api/v3`).
+///
+/// Return a vector of `SshPublicKey` containing all the user keys in the exact same order they were
+/// send by the API. Invalid keys are rejected (see `provider::parse_key`).
 ///
 /// Output keys format is the following:
 /// `<SSH_KEY> from-GH-id-<KEY_ID>`
@@ -82,70 +112,94 @@ fn validate_username(username: &str) -> bool {
 ///
 /// # Errors
 ///
-/// Return the response status code if it's not a 2XX status code.
-/// Return an internal error code:
-///   - `1001` if GitHub username isn't valid
-///     code stored in `INVALID_GH_USERNAME`
-///   - `1002` if GitHub API response could not be parsed
-///     code stored in `INVALID_GH_API_RESPONSE`
+/// Return:
+///   - `Error::InvalidUsername` if GitHub username isn't valid
+///   - `Error::NotFound` if the user doesn't exists
+///   - `Error::RateLimited` if API rate limit is exceeded (reset time is in the error)
+///   - `Error::AuthFailure` if the token is rejected
+///   - `Error::MalformedResponse` if GitHub API response could not be parsed
+///   - `Error::Transport` on connection errors
+///   - `Error::UnexpectedStatus` on any other non 2XX status code
 ///
 /// # Example
 ///
 /// ```
-/// let token: Option<String>;
-/// # token = std::env::var("GITHUB_TOKEN").ok();
-/// use superkeyloader_lib::github::get_keys;
+/// use superkeyloader_lib::github::{GithubClient, DEFAULT_BASE_URL};
+/// use superkeyloader_lib::provider::KeyProvider;
 ///
-/// let keys = get_keys("biosan", token).unwrap();
+/// let github = GithubClient::new(DEFAULT_BASE_URL, None);
+/// let enterprise = GithubClient::new("https://ghe.corp/api/v3/", None);
 ///
-/// assert!(keys[0].contains(&String::from("ssh")));
-/// assert!(keys[0].contains(&String::from(" from-GH-id-")));
+/// assert_eq!(github.name(), "github");
+/// assert_eq!(enterprise.base_url(), "https://ghe.corp/api/v3");
+/// assert!(github.validate_identity("biosan"));
+/// assert!(!github.validate_identity("biosan-"));
 /// ```
 ///
-pub fn get_keys(username: &str, token: Option<String>) -> Result<Vec<String>, u16> {
-    if !validate_username(username) {
-        return Err(INVALID_GH_USERNAME);
-    }
+pub struct GithubClient {
+    base_url: String,
+    token: Option<String>,
+}
 
-    // TODO: I don't like very much this approach... find a better way
-    #[cfg(not(test))]
-    let gh_api_url: &str = "https://api.github.com";
-    #[cfg(test)]
-    let gh_api_url: &str = &mockito::server_url();
-    debug!("GitHub API base URL: {}", gh_api_url);
+impl GithubClient {
+    pub fn new(base_url: &str, token: Option<String>) -> GithubClient {
+        GithubClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
 
-    // 1. Make HTTP request
-    // 2. Transmform reponse JSON to an array of keys
-    let url = format!("{}/users/{}/keys", gh_api_url, username);
-    debug!("GitHub API endpoint URL: {}", url);
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
 
-    let mut request = ureq::get(&url);
+impl KeyProvider for GithubClient {
+    fn name(&self) -> &str {
+        "github"
+    }
 
-    if let Some(oauth_token) = token {
-        request.set("Authorization", format!("token {}", oauth_token).as_ref());
+    fn display_name(&self) -> &str {
+        "GitHub"
     }
 
-    let response = request.call();
+    fn validate_identity(&self, identity: &str) -> bool {
+        validate_username(identity)
+    }
 
-    if !response.ok() {
-        return Err(response.status());
+    fn tag_scope(&self, _identity: &str) -> TagScope {
+        TagScope::new("GH", None)
     }
 
-    let resp_json = response.into_string().unwrap();
-    let parsed_json = serde_json::from_str(&resp_json);
+    fn get_keys(&self, username: &str) -> Result<Vec<SshPublicKey>> {
+        if !validate_username(username) {
+            return Err(Error::InvalidUsername(username.to_string()));
+        }
+        debug!("GitHub API base URL: {}", self.base_url);
 
-    if parsed_json.is_err() {
-        return Err(INVALID_GH_API_RESPONSE);
-    }
+        // 1. Make HTTP request
+        // 2. Transmform reponse JSON to an array of keys
+        let url = format!("{}/users/{}/keys", self.base_url, username);
+        debug!("GitHub API endpoint URL: {}", url);
+
+        let mut request = ureq::get(&url);
+
+        if let Some(oauth_token) = &self.token {
+            request.set("Authorization", format!("token {}", oauth_token).as_ref());
+        }
 
-    let gh_keys: Vec<GhKey> = parsed_json.unwrap();
+        let response = error::check_response(request.call(), username)?;
 
-    let keys = gh_keys
-        .into_iter()
-        .map(|key| format!("{} from-GH-id-{}", key.key, key.id))
-        .collect();
+        let resp_json = error::read_body(response)?;
+        let gh_keys: Vec<GhKey> = serde_json::from_str(&resp_json)?;
 
-    Ok(keys)
+        let keys = gh_keys
+            .into_iter()
+            .filter_map(|key| provider::parse_key(&key.key, "GH", key.id))
+            .collect();
+
+        Ok(keys)
+    }
 }
 
 pub mod test_values {
@@ -159,27 +213,46 @@ pub mod test_values {
     pub const VALID_3_KEYS_JSON: &str = r#"[
       {
         "id": 12257919,
-        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCarT/me5sWxY9Tizc"
+        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf"
       },
       {
         "id": 22932337,
-        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAACAQC+MxvBji8iUuN2so2"
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak"
       },
       {
         "id": 69196823,
-        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDq/BrJT0c7LSmTRDE"
+        "key": "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBFQC5MlQLEgyrVklawnRPtmBumdOF7oIJMGFL/ZKxmmJtdNv5bPgTHHADvXvTRrAiJQpjr/cc02E+Uf5J8N5TeE="
       }
     ]"#;
 
     pub const EMPTY_JSON: &str = r#"[]"#;
 
+    pub const INJECTED_KEYS_JSON: &str = r#"[
+      {
+        "id": 1,
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg\ncommand=\"/bin/sh\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg"
+      },
+      {
+        "id": 2,
+        "key": "command=\"/bin/sh\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg"
+      },
+      {
+        "id": 3,
+        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCarT/me5sWxY9Tizc"
+      },
+      {
+        "id": 4,
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg"
+      }
+    ]"#;
+
     pub const INVALID_JSON: &str = r#"[
       {
         "id": "12257919",
-        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCarT/me5sWxY9Tizc"
+        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf"
       },
       {
-        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAACAQC+MxvBji8iUuN2so2"
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak"
       },
       {
         "id": 69196823,
@@ -192,6 +265,9 @@ pub mod test_values {
 mod tests {
 
     use super::test_values::*;
+    use super::GithubClient;
+    use crate::error::Error;
+    use crate::provider::KeyProvider;
 
     use mockito::mock;
 
@@ -201,26 +277,24 @@ mod tests {
             .try_init();
     }
 
+    fn github() -> GithubClient {
+        GithubClient::new(&mockito::server_url(), None)
+    }
+
     #[test]
     fn test_github_username_validation() {
         init();
 
-        assert_eq!(
-            super::validate_username(&String::from(VALID_USERNAME)),
-            true
-        );
-        assert_eq!(
-            super::validate_username(&String::from(INVALID_USERNAME_LENGTH)),
-            false
-        );
-        assert_eq!(
-            super::validate_username(&String::from(INVALID_USERNAME_ENDING_HYPHEN)),
-            false
-        );
-        assert_eq!(
-            super::validate_username(&String::from(INVALID_USERNAME_CONSEC_HYPHEN)),
-            false
-        );
+        assert!(super::validate_username(&String::from(VALID_USERNAME)));
+        assert!(!super::validate_username(&String::from(
+            INVALID_USERNAME_LENGTH
+        )));
+        assert!(!super::validate_username(&String::from(
+            INVALID_USERNAME_ENDING_HYPHEN
+        )));
+        assert!(!super::validate_username(&String::from(
+            INVALID_USERNAME_CONSEC_HYPHEN
+        )));
     }
 
     #[test]
@@ -232,9 +306,9 @@ mod tests {
             .with_body(VALID_3_KEYS_JSON)
             .create();
 
-        let result = super::get_keys(&String::from(VALID_USERNAME), None);
+        let result = github().get_keys(&String::from(VALID_USERNAME));
 
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         assert_eq!(result.unwrap().len(), 3);
     }
 
@@ -247,10 +321,25 @@ mod tests {
             .with_body(INVALID_JSON)
             .create();
 
-        let result = super::get_keys(&String::from(VALID_USERNAME), None);
+        let result = github().get_keys(&String::from(VALID_USERNAME));
+
+        assert!(matches!(result, Err(Error::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn invalid_keys_rejected() {
+        init();
+        let _m = mock("GET", "/users/testuser/keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_body(INJECTED_KEYS_JSON)
+            .create();
+
+        let keys = github().get_keys(VALID_USERNAME).unwrap();
 
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err().unwrap(), super::INVALID_GH_API_RESPONSE);
+        // Injected lines, options and truncated keys are all rejected
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].comment(), Some("from-GH-id-4"));
     }
 
     #[test]
@@ -262,9 +351,9 @@ mod tests {
             .with_body(EMPTY_JSON)
             .create();
 
-        let result = super::get_keys(&String::from(VALID_USERNAME), None);
+        let result = github().get_keys(&String::from(VALID_USERNAME));
 
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         assert_eq!(result.unwrap().len(), 0);
     }
 
@@ -277,10 +366,9 @@ mod tests {
             .with_body(VALID_3_KEYS_JSON)
             .create();
 
-        let result = super::get_keys(&String::from(MISSING_USERNAME), None);
+        let result = github().get_keys(&String::from(MISSING_USERNAME));
 
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err().unwrap(), 404);
+        assert!(matches!(result, Err(Error::NotFound(_))));
     }
 
     #[test]
@@ -293,18 +381,67 @@ mod tests {
             .create();
 
         // Test 'too long' username case
-        let result = super::get_keys(&String::from(INVALID_USERNAME_LENGTH), None);
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err().unwrap(), super::INVALID_GH_USERNAME);
+        let result = github().get_keys(&String::from(INVALID_USERNAME_LENGTH));
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
 
         // Test 'ending with hyphen' username case
-        let result = super::get_keys(&String::from(INVALID_USERNAME_ENDING_HYPHEN), None);
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err().unwrap(), super::INVALID_GH_USERNAME);
+        let result = github().get_keys(&String::from(INVALID_USERNAME_ENDING_HYPHEN));
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
 
         // Test 'two consecutive' username case
-        let result = super::get_keys(&String::from(INVALID_USERNAME_CONSEC_HYPHEN), None);
-        assert_eq!(result.is_ok(), false);
-        assert_eq!(result.err().unwrap(), super::INVALID_GH_USERNAME);
+        let result = github().get_keys(&String::from(INVALID_USERNAME_CONSEC_HYPHEN));
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
+    }
+
+    #[test]
+    fn provider_trait() {
+        init();
+        let _m = mock("GET", "/users/testuser/keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_body(VALID_3_KEYS_JSON)
+            .create();
+
+        let github = github();
+
+        assert!(!github.validate_identity(INVALID_USERNAME_CONSEC_HYPHEN));
+        assert_eq!(github.get_keys(VALID_USERNAME).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn enterprise_base_url() {
+        init();
+        let _m = mock("GET", "/api/v3/users/testuser/keys")
+            .match_header("Authorization", "token secret")
+            .with_status(200)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_body(VALID_3_KEYS_JSON)
+            .create();
+
+        let base_url = format!("{}/api/v3/", mockito::server_url());
+        let github = GithubClient::new(&base_url, Some("secret".to_string()));
+
+        assert_eq!(github.get_keys(VALID_USERNAME).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rate_limited() {
+        init();
+        let _m = mock("GET", "/users/testuser/keys")
+            .with_status(403)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_header("X-RateLimit-Remaining", "0")
+            .with_header("X-RateLimit-Reset", "1585699200")
+            .with_body(r#"{"message": "API rate limit exceeded"}"#)
+            .create();
+
+        let result = github().get_keys(&String::from(VALID_USERNAME));
+
+        assert!(matches!(
+            result,
+            Err(Error::RateLimited {
+                reset: Some(1585699200)
+            })
+        ));
     }
 }
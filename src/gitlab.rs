@@ -0,0 +1,285 @@
+extern crate pretty_env_logger;
+
+use regex::{Regex, RegexSet};
+
+use crate::error::{self, Error, Result};
+use crate::provider::{self, KeyProvider, TagScope};
+use crate::ssh_key::SshPublicKey;
+
+/// Public GitLab instance, used when no other base URL is set
+pub const DEFAULT_BASE_URL: &str = "https://gitlab.com";
+
+///
+/// GitLab API response parsing struct (REST v4)
+///
+/// [Documentation](https://docs.gitlab.com/ee/api/users.html#list-ssh-keys-for-user)
+///
+/// URL: `GET https://<HOST>/api/v4/users/<USERNAME>/keys`
+///
+/// > The API also returns `title`, `created_at` and `expires_at`, they're just ignored
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::gitlab::GlKey;
+///
+/// let json_string = r#"
+///   [
+///     {
+///       "id": 1121029,
+///       "title": "laptop",
+///       "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHk"
+///     }
+///   ]
+/// "#;
+/// let keys: Vec<GlKey> = serde_json::from_str(&json_string).unwrap();
+///
+/// assert_eq!(keys[0].id, 1121029);
+/// assert_eq!(keys[0].key, "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHk");
+/// ```
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GlKey {
+    pub id: u64,
+    pub key: String,
+}
+
+///
+/// Validate GitLab usernames
+///
+/// # Rules
+///   - Max 255 characters, alphanumerical, '_', '-' and '.'
+///   - Must start with an alphanumerical character or '_'
+///   - Cannot end with '.', '.git' or '.atom'
+///   - Cannot have consecutive special characters ('_', '-' or '.')
+///
+fn validate_username(username: &str) -> bool {
+    let username_rules =
+        RegexSet::new(vec![r"^[a-zA-Z\d_][-a-zA-Z\d_.]{0,254}$", r".*[^.]$"]).unwrap();
+    let forbidden = Regex::new(r"([-_.]{2}|\.git$|\.atom$)").unwrap();
+
+    let matches: Vec<_> = username_rules.matches(username).into_iter().collect();
+    // If all rules match and there are no forbidden patterns then the username is valid
+    username_rules.len() == matches.len() && !forbidden.is_match(username)
+}
+
+///
+/// GitLab key provider (gitlab.com and self-hosted instances)
+///
+/// Output keys format is the following:
+/// `<SSH_KEY> from-GL-id-<KEY_ID>`
+///
+/// > `KEY_ID` is the internal GitLab key id.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::gitlab::GitlabClient;
+/// use superkeyloader_lib::provider::KeyProvider;
+///
+/// let gitlab = GitlabClient::new("https://gitlab.example.com/", None);
+///
+/// assert_eq!(gitlab.name(), "gitlab");
+/// assert_eq!(gitlab.base_url(), "https://gitlab.example.com");
+/// assert!(gitlab.validate_identity("alice.smith"));
+/// assert!(!gitlab.validate_identity("alice.git"));
+/// ```
+///
+pub struct GitlabClient {
+    base_url: String,
+    token: Option<String>,
+}
+
+impl GitlabClient {
+    pub fn new(base_url: &str, token: Option<String>) -> GitlabClient {
+        GitlabClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
+impl KeyProvider for GitlabClient {
+    fn name(&self) -> &str {
+        "gitlab"
+    }
+
+    fn display_name(&self) -> &str {
+        "GitLab"
+    }
+
+    fn validate_identity(&self, identity: &str) -> bool {
+        validate_username(identity)
+    }
+
+    fn tag_scope(&self, _identity: &str) -> TagScope {
+        TagScope::new("GL", None)
+    }
+
+    ///
+    /// Download user's SSH keys from GitLab
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidUsername` if GitLab username isn't valid,
+    /// `Error::MalformedResponse` if GitLab API response could not be parsed,
+    /// and the errors of `error::check_response` for non 2XX responses.
+    ///
+    fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>> {
+        if !validate_username(identity) {
+            return Err(Error::InvalidUsername(identity.to_string()));
+        }
+
+        let url = format!("{}/api/v4/users/{}/keys", self.base_url, identity);
+        debug!("GitLab API endpoint URL: {}", url);
+
+        let mut request = ureq::get(&url);
+
+        if let Some(private_token) = &self.token {
+            request.set("PRIVATE-TOKEN", private_token);
+        }
+
+        let response = error::check_response(request.call(), identity)?;
+
+        let resp_json = error::read_body(response)?;
+        let gl_keys: Vec<GlKey> = serde_json::from_str(&resp_json)?;
+
+        let keys = gl_keys
+            .into_iter()
+            .filter_map(|key| provider::parse_key(&key.key, "GL", key.id))
+            .collect();
+
+        Ok(keys)
+    }
+}
+
+pub mod test_values {
+
+    pub const VALID_USERNAME: &str = "test.user";
+    pub const MISSING_USERNAME: &str = "erruser";
+    pub const INVALID_USERNAME_START: &str = "-user";
+    pub const INVALID_USERNAME_ENDING_DOT: &str = "user.";
+    pub const INVALID_USERNAME_ENDING_GIT: &str = "user.git";
+    pub const INVALID_USERNAME_CONSEC_SPECIAL: &str = "user._user";
+
+    pub const VALID_2_KEYS_JSON: &str = r#"[
+      {
+        "id": 1121029,
+        "title": "laptop",
+        "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf",
+        "created_at": "2020-01-24T13:23:59.171Z",
+        "expires_at": null
+      },
+      {
+        "id": 1121030,
+        "title": "desktop",
+        "key": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak",
+        "created_at": "2020-02-12T09:01:12.001Z",
+        "expires_at": null
+      }
+    ]"#;
+
+    pub const INVALID_JSON: &str = r#"{"message": "not a list of keys"}"#;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_values::*;
+    use super::GitlabClient;
+    use crate::error::Error;
+    use crate::provider::KeyProvider;
+
+    use mockito::{mock, Matcher};
+
+    fn init() {
+        let _ = pretty_env_logger::env_logger::builder()
+            .is_test(true)
+            .try_init();
+    }
+
+    #[test]
+    fn test_gitlab_username_validation() {
+        init();
+
+        assert!(super::validate_username(VALID_USERNAME));
+        assert!(!super::validate_username(INVALID_USERNAME_START));
+        assert!(!super::validate_username(INVALID_USERNAME_ENDING_DOT));
+        assert!(!super::validate_username(INVALID_USERNAME_ENDING_GIT));
+        assert!(!super::validate_username(INVALID_USERNAME_CONSEC_SPECIAL));
+    }
+
+    #[test]
+    fn valid_response() {
+        init();
+        let _m = mock("GET", "/api/v4/users/test.user/keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(VALID_2_KEYS_JSON)
+            .create();
+
+        let gitlab = GitlabClient::new(&mockito::server_url(), None);
+        let keys = gitlab.get_keys(VALID_USERNAME).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].comment(), Some("from-GL-id-1121029"));
+    }
+
+    #[test]
+    fn private_token() {
+        init();
+        let _m = mock("GET", "/api/v4/users/test.user/keys")
+            .match_header("PRIVATE-TOKEN", Matcher::Exact("secret".into()))
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(VALID_2_KEYS_JSON)
+            .create();
+
+        let gitlab = GitlabClient::new(&mockito::server_url(), Some("secret".into()));
+
+        assert_eq!(gitlab.get_keys(VALID_USERNAME).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_response() {
+        init();
+        let _m = mock("GET", "/api/v4/users/test.user/keys")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(INVALID_JSON)
+            .create();
+
+        let gitlab = GitlabClient::new(&mockito::server_url(), None);
+        let result = gitlab.get_keys(VALID_USERNAME);
+
+        assert!(matches!(result, Err(Error::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn missing_username() {
+        init();
+        let _m = mock("GET", "/api/v4/users/erruser/keys")
+            .with_status(404)
+            .with_header("Content-Type", "application/json")
+            .with_body(r#"{"message":"404 User Not Found"}"#)
+            .create();
+
+        let gitlab = GitlabClient::new(&mockito::server_url(), None);
+        let result = gitlab.get_keys(MISSING_USERNAME);
+
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn invalid_username() {
+        init();
+        let gitlab = GitlabClient::new(&mockito::server_url(), None);
+        let result = gitlab.get_keys(INVALID_USERNAME_ENDING_GIT);
+
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
+    }
+}
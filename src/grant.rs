@@ -0,0 +1,201 @@
+use crate::authorized_keys::KeyLine;
+use crate::error::{Error, Result};
+use crate::key_options::KeyOptions;
+use crate::ssh_key::SshPublicKey;
+use crate::time::format_timestamp;
+
+/// Prefix of the grant tag, followed by the UTC expiry time (i.e. `grant-until-20261231T235959Z`)
+pub const GRANT_TAG: &str = "grant-until-";
+
+///
+/// Parse a grant duration: a number followed by `s`, `m`, `h`, `d` or `w` (i.e. `8h`, `2w`)
+///
+/// # Errors
+///
+/// Return `Error::InvalidDuration` for any other text, and for zero durations.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::grant::parse_duration;
+///
+/// assert_eq!(parse_duration("8h").unwrap(), 8 * 3600);
+/// assert_eq!(parse_duration("30m").unwrap(), 1800);
+/// assert!(parse_duration("8").is_err());
+/// assert!(parse_duration("1y").is_err());
+/// ```
+///
+pub fn parse_duration(text: &str) -> Result<u64> {
+    let invalid = || Error::InvalidDuration(text.to_string());
+    let text = text.trim();
+
+    let unit = match text.chars().last().ok_or_else(invalid)? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        _ => return Err(invalid()),
+    };
+    let number: u64 = text[..text.len() - 1].parse().map_err(|_| invalid())?;
+
+    match number.checked_mul(unit) {
+        Some(seconds) if seconds > 0 => Ok(seconds),
+        _ => Err(invalid()),
+    }
+}
+
+///
+/// Limit a key in time: add the `expiry-time` option and record the grant in the comment
+///
+/// The grant tag is written right before the origin tag, that stays the last word:
+///
+/// `expiry-time="<TIME>" <SSH_KEY> [<COMMENT>] grant-until-<TIME> from-<SOURCE>-id-<KEY_ID>`
+///
+/// Times are in UTC. Options already set are kept (an old `expiry-time` is replaced).
+///
+/// # Errors
+///
+/// Return `Error::InvalidOptions` if the key has invalid options.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::grant::grant_key;
+/// use superkeyloader_lib::ssh_key::SshPublicKey;
+///
+/// let key = SshPublicKey::parse(
+///     "restrict ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg \
+///      alice@laptop from-GH-id-1",
+/// )
+/// .unwrap();
+/// let key = grant_key(&key, 1798761599).unwrap();
+///
+/// assert_eq!(key.options(), Some(r#"restrict,expiry-time="20261231235959Z""#));
+/// assert_eq!(
+///     key.comment(),
+///     Some("alice@laptop grant-until-20261231T235959Z from-GH-id-1")
+/// );
+/// ```
+///
+pub fn grant_key(key: &SshPublicKey, until: u64) -> Result<SshPublicKey> {
+    let timestamp = format_timestamp(until);
+
+    let mut options = match key.options() {
+        Some(options) => KeyOptions::parse(options)?,
+        None => KeyOptions::default(),
+    };
+    options.set("expiry-time", Some(&timestamp.replace('T', "")))?;
+
+    // Old grant tags are dropped, the new one goes before the origin tag (if any)
+    let grant = format!("{}{}", GRANT_TAG, timestamp);
+    let mut words: Vec<&str> = key
+        .comment()
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|word| !word.starts_with(GRANT_TAG))
+        .collect();
+    let position = match words.last() {
+        Some(tag) if tag.starts_with("from-") => words.len() - 1,
+        _ => words.len(),
+    };
+    words.insert(position, &grant);
+
+    key.clone()
+        .with_options(Some(&options))
+        .with_comment(Some(&words.join(" ")))
+}
+
+///
+/// Grant expiry time of a key line (as written in its grant tag), `None` if it isn't time-limited
+///
+pub fn grant_until(line: &str) -> Option<String> {
+    KeyLine::parse(line)?
+        .comment?
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(GRANT_TAG))
+        .find(|timestamp| is_timestamp(timestamp))
+        .map(String::from)
+}
+
+///
+/// Check if the grant of a key line is expired at `now` (UNIX time)
+///
+pub fn is_expired(line: &str, now: u64) -> bool {
+    match grant_until(line) {
+        // Timestamps have a fixed width, so they sort like the times they represent
+        Some(until) => until < format_timestamp(now),
+        None => false,
+    }
+}
+
+///
+/// `YYYYMMDDTHHMMSSZ`, like `time::format_timestamp` writes it
+///
+fn is_timestamp(text: &str) -> bool {
+    text.len() == 16
+        && text.char_indices().all(|(index, c)| match index {
+            8 => c == 'T',
+            15 => c == 'Z',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{grant_key, grant_until, is_expired, parse_duration};
+    use crate::error::Error;
+    use crate::ssh_key::test_values::ED25519_KEY;
+    use crate::ssh_key::SshPublicKey;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration(" 7d ").unwrap(), 7 * 86400);
+        assert_eq!(parse_duration("2w").unwrap(), 14 * 86400);
+
+        for invalid in [
+            "",
+            "h",
+            "0h",
+            "-1h",
+            "1.5h",
+            "8 h",
+            "8hours",
+            "99999999999999999999w",
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(parse_duration(invalid), Err(Error::InvalidDuration(_))),
+                "'{}' should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn grants() {
+        let key = SshPublicKey::parse(ED25519_KEY).unwrap();
+        let key = grant_key(&key, 1585699200).unwrap();
+        let line = key.to_string();
+
+        assert_eq!(key.options(), Some(r#"expiry-time="20200401000000Z""#));
+        assert_eq!(key.comment(), Some("grant-until-20200401T000000Z"));
+        assert_eq!(grant_until(&line).as_deref(), Some("20200401T000000Z"));
+
+        assert!(!is_expired(&line, 1585699200));
+        assert!(is_expired(&line, 1585699201));
+        assert!(!is_expired(ED25519_KEY, 1585699201));
+        assert!(!is_expired(
+            &format!("{} grant-until-tomorrow from-GH-id-1", ED25519_KEY),
+            1585699201
+        ));
+
+        // A new grant replaces the old one
+        let key = grant_key(&key, 1709251199).unwrap();
+        assert_eq!(key.options(), Some(r#"expiry-time="20240229235959Z""#));
+        assert_eq!(key.comment(), Some("grant-until-20240229T235959Z"));
+    }
+}
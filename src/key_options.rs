@@ -0,0 +1,445 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::error::{Error, Result};
+
+/// Options without a value (i.e. `no-pty`)
+pub const FLAG_OPTIONS: &[&str] = &[
+    "agent-forwarding",
+    "cert-authority",
+    "no-agent-forwarding",
+    "no-port-forwarding",
+    "no-pty",
+    "no-touch-required",
+    "no-user-rc",
+    "no-x11-forwarding",
+    "port-forwarding",
+    "pty",
+    "restrict",
+    "user-rc",
+    "verify-required",
+    "x11-forwarding",
+];
+
+/// Options with a quoted value (i.e. `from="10.0.0.0/8"`)
+pub const VALUE_OPTIONS: &[&str] = &[
+    "command",
+    "environment",
+    "expiry-time",
+    "from",
+    "permitlisten",
+    "permitopen",
+    "principals",
+    "tunnel",
+];
+
+/// Options that can be given more than once
+const REPEATABLE_OPTIONS: &[&str] = &["environment", "permitlisten", "permitopen"];
+
+///
+/// A single `authorized_keys` option, with its unquoted value
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyOption {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl fmt::Display for KeyOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}=\"{}\"", self.name, value.replace('"', "\\\"")),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+///
+/// Validated `authorized_keys` options, as written before the key type
+///
+/// Unknown options, missing or unexpected values and malformed values (addresses, dates, ports)
+/// are rejected, so a typo can't produce a line that sshd refuses or, worse, that it accepts
+/// without the restriction.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::key_options::KeyOptions;
+///
+/// let options = KeyOptions::parse(r#"restrict,from="10.0.0.0/8",expiry-time="20261231""#).unwrap();
+///
+/// assert_eq!(options.get("from"), Some("10.0.0.0/8"));
+/// assert_eq!(
+///     options.to_string(),
+///     r#"restrict,from="10.0.0.0/8",expiry-time="20261231""#
+/// );
+///
+/// assert!(KeyOptions::parse("no-port-fowarding").is_err());
+/// assert!(KeyOptions::parse(r#"from="10.0.0.0/33""#).is_err());
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyOptions {
+    options: Vec<KeyOption>,
+}
+
+impl KeyOptions {
+    ///
+    /// Parse and validate comma separated options
+    ///
+    /// Option names are case insensitive (like sshd does) and are stored in lower case.
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidOptions` with the reason if any option is invalid.
+    ///
+    pub fn parse(text: &str) -> Result<KeyOptions> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(invalid("no options given"));
+        }
+        let mut options = KeyOptions::default();
+        for field in split_options(text)? {
+            options.push(parse_option(field)?)?;
+        }
+
+        Ok(options)
+    }
+
+    ///
+    /// Value of the first `name` option (`None` for missing options and flags)
+    ///
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| option.value.as_deref())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.options.iter().any(|option| option.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KeyOption> {
+        self.options.iter()
+    }
+
+    ///
+    /// Add an option, or replace its value if it can't be repeated
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidOptions` if the option (or its value) is invalid.
+    ///
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<()> {
+        let option = KeyOption {
+            name: name.to_lowercase(),
+            value: value.map(String::from),
+        };
+        check_option(&option)?;
+
+        if !REPEATABLE_OPTIONS.contains(&option.name.as_str()) {
+            self.options.retain(|present| present.name != option.name);
+        }
+        self.options.push(option);
+        Ok(())
+    }
+
+    fn push(&mut self, option: KeyOption) -> Result<()> {
+        if self.contains(&option.name) && !REPEATABLE_OPTIONS.contains(&option.name.as_str()) {
+            return Err(invalid(&format!(
+                "option '{}' given more than once",
+                option.name
+            )));
+        }
+        self.options.push(option);
+        Ok(())
+    }
+}
+
+impl fmt::Display for KeyOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options: Vec<String> = self.options.iter().map(ToString::to_string).collect();
+        write!(f, "{}", options.join(","))
+    }
+}
+
+impl std::str::FromStr for KeyOptions {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<KeyOptions> {
+        KeyOptions::parse(text)
+    }
+}
+
+fn invalid(why: &str) -> Error {
+    Error::InvalidOptions(why.to_string())
+}
+
+///
+/// Split options on commas, except the ones inside double quotes
+///
+fn split_options(text: &str) -> Result<Vec<&str>> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, character) in text.char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(invalid("missing closing quote"));
+    }
+    fields.push(&text[start..]);
+
+    Ok(fields)
+}
+
+fn parse_option(field: &str) -> Result<KeyOption> {
+    if field.is_empty() {
+        return Err(invalid("empty option (stray comma)"));
+    }
+
+    let option = match field.find('=') {
+        Some(index) => {
+            let quoted = &field[index + 1..];
+            if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"') {
+                return Err(invalid(&format!(
+                    "value of '{}' must be enclosed in double quotes",
+                    &field[..index]
+                )));
+            }
+            let value = &quoted[1..quoted.len() - 1];
+            if value.replace("\\\"", "").contains('"') {
+                return Err(invalid(&format!(
+                    "quotes in the value of '{}' must be escaped",
+                    &field[..index]
+                )));
+            }
+            KeyOption {
+                name: field[..index].to_lowercase(),
+                value: Some(value.replace("\\\"", "\"")),
+            }
+        }
+        None => KeyOption {
+            name: field.to_lowercase(),
+            value: None,
+        },
+    };
+    check_option(&option)?;
+
+    Ok(option)
+}
+
+fn check_option(option: &KeyOption) -> Result<()> {
+    let name = option.name.as_str();
+    let value = match (&option.value, FLAG_OPTIONS.contains(&name)) {
+        (None, true) => return Ok(()),
+        (Some(_), true) => return Err(invalid(&format!("option '{}' doesn't take a value", name))),
+        (None, false) if VALUE_OPTIONS.contains(&name) => {
+            return Err(invalid(&format!("option '{}' requires a value", name)))
+        }
+        (None, false) => return Err(invalid(&format!("unknown option '{}'", name))),
+        (Some(_), false) if !VALUE_OPTIONS.contains(&name) => {
+            return Err(invalid(&format!("unknown option '{}'", name)))
+        }
+        (Some(value), false) => value.as_str(),
+    };
+
+    if value.chars().any(char::is_control) || value.ends_with('\\') {
+        return Err(invalid(&format!(
+            "value of '{}' has control characters or a trailing backslash",
+            name
+        )));
+    }
+    if value.is_empty() {
+        return Err(invalid(&format!("value of '{}' is empty", name)));
+    }
+
+    let valid = match name {
+        "from" => value.split(',').all(check_host_pattern),
+        "expiry-time" => check_time(value),
+        "permitopen" => check_host_port(value, true),
+        "permitlisten" => check_host_port(value, false),
+        "tunnel" => value.parse::<u32>().is_ok(),
+        "environment" => match value.find('=') {
+            Some(index) if index > 0 => value[..index]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            _ => false,
+        },
+        "principals" => value.split(',').all(|principal| !principal.is_empty()),
+        _ => true,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid(&format!(
+            "invalid value \"{}\" for option '{}'",
+            value, name
+        )))
+    }
+}
+
+///
+/// Host name, address or CIDR block, with optional `*`/`?` wildcards and `!` negation
+///
+fn check_host_pattern(pattern: &str) -> bool {
+    let pattern = pattern.strip_prefix('!').unwrap_or(pattern);
+    if pattern.is_empty() {
+        return false;
+    }
+
+    match pattern.find('/') {
+        Some(index) => {
+            let max_bits = match pattern[..index].parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => 32,
+                Ok(IpAddr::V6(_)) => 128,
+                Err(_) => return false,
+            };
+            matches!(pattern[index + 1..].parse::<u8>(), Ok(bits) if bits <= max_bits)
+        }
+        None => pattern
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:_*?".contains(c)),
+    }
+}
+
+///
+/// `host:port` (`[address]:port` for IPv6), port can be `*`; `host:` is optional for
+/// `permitlisten`
+///
+fn check_host_port(value: &str, host_required: bool) -> bool {
+    let (host, port) = match value.rfind(':') {
+        Some(index) => (Some(&value[..index]), &value[index + 1..]),
+        None if !host_required => (None, value),
+        None => return false,
+    };
+
+    let port_valid = port == "*" || matches!(port.parse::<u16>(), Ok(port) if port > 0);
+    let host_valid = match host {
+        Some(host) if host.starts_with('[') && host.ends_with(']') => {
+            host[1..host.len() - 1].parse::<IpAddr>().is_ok()
+        }
+        Some(host) => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._*".contains(c))
+        }
+        None => true,
+    };
+
+    port_valid && host_valid
+}
+
+///
+/// `YYYYMMDD[HHMM[SS]]` with an optional trailing `Z` (UTC)
+///
+fn check_time(value: &str) -> bool {
+    let digits = value.strip_suffix('Z').unwrap_or(value);
+    if ![8, 12, 14].contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    // Month, day, hour, minute and second limits, only for the fields present
+    let limits = [(1, 12), (1, 31), (0, 23), (0, 59), (0, 59)];
+    digits.as_bytes()[4..]
+        .chunks(2)
+        .zip(limits.iter())
+        .all(|(field, (min, max))| {
+            let number = u32::from(field[0] - b'0') * 10 + u32::from(field[1] - b'0');
+            number >= *min && number <= *max
+        })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{split_options, KeyOptions};
+    use crate::error::Error;
+
+    #[test]
+    fn options_splitting() {
+        assert_eq!(
+            split_options(r#"no-pty,command="a,b \"c\"",restrict"#).unwrap(),
+            vec!["no-pty", r#"command="a,b \"c\"""#, "restrict"]
+        );
+        assert!(split_options(r#"command="echo"#).is_err());
+    }
+
+    #[test]
+    fn valid_options() {
+        let options = KeyOptions::parse(
+            r#"restrict,No-Pty,command="echo \"hello world\"",permitopen="localhost:80",permitopen="[::1]:*",permitlisten="8080",environment="LANG=C",tunnel="0",from="10.0.0.0/8,!10.1.*,*.example.com,2001:db8::/32",expiry-time="202612312359Z",principals="alice,bob""#,
+        )
+        .unwrap();
+
+        assert!(options.contains("no-pty"));
+        assert_eq!(options.get("command"), Some(r#"echo "hello world""#));
+        assert_eq!(options.iter().count(), 11);
+        assert_eq!(KeyOptions::parse(&options.to_string()).unwrap(), options);
+    }
+
+    #[test]
+    fn invalid_options() {
+        let invalid = [
+            "",
+            "restrict,",
+            "restrict,,no-pty",
+            "no-port-fowarding",
+            "restrict=\"yes\"",
+            "command",
+            "command=echo",
+            "command=\"\"",
+            "command=\"echo\",command=\"ls\"",
+            "command=\"echo\nls\"",
+            "command=\"a\"\"b\"",
+            "restrict no-pty",
+            "from=\"10.0.0.0/33\"",
+            "from=\"300.0.0.0/8\"",
+            "from=\"host name\"",
+            "from=\"a,,b\"",
+            "expiry-time=\"2026-12-31\"",
+            "expiry-time=\"20261331\"",
+            "expiry-time=\"2026123123\"",
+            "permitopen=\"localhost\"",
+            "permitopen=\"localhost:http\"",
+            "permitopen=\"[localhost]:80\"",
+            "permitlisten=\"0\"",
+            "environment=\"=C\"",
+            "environment=\"LANG\"",
+            "tunnel=\"tun0\"",
+        ];
+
+        for options in invalid.iter() {
+            assert!(
+                matches!(KeyOptions::parse(options), Err(Error::InvalidOptions(_))),
+                "'{}' should be invalid",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn set_options() {
+        let mut options = KeyOptions::parse("restrict").unwrap();
+        options.set("expiry-time", Some("20260101")).unwrap();
+        options.set("expiry-time", Some("20261231")).unwrap();
+
+        assert_eq!(options.to_string(), r#"restrict,expiry-time="20261231""#);
+        assert!(options.set("expiry-time", Some("tomorrow")).is_err());
+        assert!(options.set("nope", None).is_err());
+    }
+}
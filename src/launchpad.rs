@@ -0,0 +1,61 @@
+use regex::Regex;
+
+use crate::plain_keys::PlainKeysClient;
+
+/// Launchpad base URL
+pub const DEFAULT_BASE_URL: &str = "https://launchpad.net";
+
+///
+/// Validate Launchpad usernames (a leading `~` is allowed and ignored)
+///
+/// # Rules
+///   - Lowercase alphanumerical, '+', '-' and '.'
+///   - Must start with an alphanumerical character and be at least 2 characters long
+///
+fn validate_username(username: &str) -> bool {
+    let username_rule = Regex::new(r"^[a-z\d][-a-z\d+.]+$").unwrap();
+    username_rule.is_match(username.trim_start_matches('~'))
+}
+
+///
+/// Launchpad key provider
+///
+/// URL: `GET https://launchpad.net/~<USERNAME>/+sshkeys`
+///
+/// The response is plain text, one key per line (the same endpoint used by Ubuntu installer and
+/// `ssh-import-id`). Keys are tagged `from-LP-id-<USERNAME>` (see `PlainKeysClient`).
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::launchpad::{self, DEFAULT_BASE_URL};
+/// use superkeyloader_lib::provider::KeyProvider;
+///
+/// let launchpad = launchpad::client(DEFAULT_BASE_URL);
+///
+/// assert_eq!(launchpad.name(), "launchpad");
+/// assert_eq!(launchpad.url("~alice"), "https://launchpad.net/~alice/+sshkeys");
+/// assert!(launchpad.validate_identity("~alice"));
+/// assert!(!launchpad.validate_identity("Alice"));
+/// ```
+///
+pub fn client(base_url: &str) -> PlainKeysClient {
+    PlainKeysClient::new(
+        ("launchpad", "Launchpad"),
+        "LP",
+        &format!("{}/~{{username}}/+sshkeys", base_url.trim_end_matches('/')),
+        validate_username,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_launchpad_username_validation() {
+        assert!(super::validate_username("testuser"));
+        assert!(super::validate_username("~test.user+ubuntu"));
+        assert!(!super::validate_username("Test_User"));
+        assert!(!super::validate_username("a"));
+    }
+}
@@ -7,30 +7,42 @@ pub extern crate serde_derive;
 pub use exitfailure::ExitDisplay;
 pub use failure::ResultExt;
 
+pub mod authorized_keys;
+pub mod backup;
+pub mod bitbucket;
+pub mod diff;
+pub mod error;
+pub mod gitea;
 pub mod github;
+pub mod gitlab;
+pub mod grant;
+pub mod key_options;
+pub mod launchpad;
+pub mod plain_keys;
+pub mod policy;
+pub mod provider;
+pub mod sourcehut;
+pub mod ssh_key;
+pub mod time;
+pub mod url;
 
 pub use github as gh;
 
+use authorized_keys::{AuthorizedKeys, Changes, KeyLine};
+use error::{Error, Result};
+use key_options::KeyOptions;
+use policy::{KeyPolicy, RejectedKey};
+use provider::{ProviderOptions, TagScope};
+use ssh_key::SshPublicKey;
+
 ///
-/// Handle HTTP status codes errors and "no SSH keys" error.
-///
-/// TODO: Add context about username and service to improve error messages
+/// Handle the "no SSH keys" error.
 ///
 /// # Errors
 ///
-/// The function use the crate `exitfailure` to print a better error message on exit.
-/// It returns an error string that contains the error description on:
-///   - Input vector length is 0
-///   - Input has no `Ok` result, but has error code:
-///     - `404` HTTP Status Code -> Usually it means that the user doesn't exists
-///     - `1001` Internal error -> GitHub username is invalid
-///         (see https://github.com/shinnn/github-username-regex)
-///     - `1002` Internal error -> GitHub API response could not be parsed
-///     - Every other status code -> Unrecognized HTTP status code (i.e. 500, 501, etc.)
-///
-/// > Assuming that a 2XX response will always have an `Ok` value, so it will never reach
-/// > 'error matching'
-///
+/// Provider errors (see `error::Error`) are returned as they are, and an empty key list is turned
+/// into `Error::NoKeys`. Every error has a human readable message (`Display`), used by the CLI
+/// to print a better error message on exit (with the crate `exitfailure`).
 ///
 /// # Examples
 ///
@@ -46,82 +58,658 @@ pub use github as gh;
 /// assert_eq!(data, output.unwrap());
 /// ```
 ///
-/// ## Missing user (404 status code)
+/// ## Missing user
 ///
 /// ```
+/// use superkeyloader_lib::error::Error;
 /// use superkeyloader_lib::error_handler_wrapper;
 ///
-/// let error_code: u16 = 404;
-/// let input = Err(error_code);
-/// let output = error_handler_wrapper(input);
+/// let input = Err(Error::NotFound("alice".to_string()));
+/// let output = error_handler_wrapper::<String>(input);
 ///
 /// assert!(output.is_err());
 ///
 /// let expected_output = String::from("Wrong username");
-/// let error_message = output.err().unwrap();
+/// let error_message = output.err().unwrap().to_string();
 ///
 /// assert!(error_message.contains(&expected_output));
 /// ```
 ///
-pub fn error_handler_wrapper(res: Result<Vec<String>, u16>) -> Result<Vec<String>, String> {
+pub fn error_handler_wrapper<T>(res: Result<Vec<T>>) -> Result<Vec<T>> {
     match res {
         Ok(res) => match res.len() {
-            0 => Err("User has no SSH keys available".into()),
+            0 => Err(Error::NoKeys),
             _ => Ok(res),
         },
-        Err(err) => match err {
-            404 => Err("Wrong username, doesn't exists".into()),
-            gh::INVALID_GH_API_RESPONSE => Err("Invalid GitHub API response".into()),
-            gh::INVALID_GH_USERNAME => {
-                Err(format!(
-                    "Invalid username. Username isn't allowed on GitHub. \
-                            If you think this is an bug, please create a issue on at {}/issues",
-                    env!("CARGO_PKG_REPOSITORY")
-                )) // TODO: Maybe add this message to all error infos?
+        Err(err) => Err(err),
+    }
+}
+
+///
+/// Keys downloaded for a single identity, or the reason why they couldn't be downloaded
+///
+#[derive(Debug)]
+pub struct IdentityKeys {
+    /// Identity as passed by the user (i.e. `gh:alice`), keys URLs without credentials and query
+    /// string (see `url::public_url`)
+    pub identity: String,
+    /// Canonical identity (i.e. `gh:alice` for `alice`), used as managed block name
+    pub name: String,
+    /// Provider display name (i.e. `GitHub`), empty if the provider is unknown
+    pub provider: String,
+    /// Downloaded keys or the reason why they couldn't be downloaded
+    pub keys: Result<Vec<SshPublicKey>>,
+    /// Downloaded keys not allowed by the key policy (see `apply_policy`)
+    pub rejected: Vec<RejectedKey>,
+    /// Origin tags of the identity keys, `None` if the provider is unknown
+    pub scope: Option<TagScope>,
+}
+
+impl IdentityKeys {
+    ///
+    /// Move the keys not allowed by `policy` to `rejected`, so they never reach the writer
+    ///
+    /// If every key is rejected the identity has no keys left, in sync mode its block is emptied.
+    ///
+    pub fn apply_policy(&mut self, policy: &KeyPolicy) {
+        if let Ok(keys) = &mut self.keys {
+            let (allowed, rejected) = policy::apply(policy, std::mem::take(keys));
+            *keys = allowed;
+            self.rejected.extend(rejected);
+        }
+    }
+
+    ///
+    /// Set `authorized_keys` options of every key, replacing the ones it already has
+    ///
+    pub fn apply_options(&mut self, options: &KeyOptions) {
+        if let Ok(keys) = &mut self.keys {
+            *keys = std::mem::take(keys)
+                .into_iter()
+                .map(|key| key.with_options(Some(options)))
+                .collect();
+        }
+    }
+
+    ///
+    /// Limit every key in time, till `until` (UNIX time), see `grant::grant_key`
+    ///
+    /// If a key can't be granted the identity fails, nothing is written without its expiry.
+    ///
+    pub fn apply_grant(&mut self, until: u64) {
+        if let Ok(keys) = &self.keys {
+            self.keys = keys
+                .iter()
+                .map(|key| grant::grant_key(key, until))
+                .collect();
+        }
+    }
+}
+
+///
+/// Download the keys of a (optionally prefixed) identity
+///
+/// Identities without a prefix (i.e. `alice` instead of `gl:alice`) use `default_provider`.
+/// Errors are handled by `error_handler_wrapper`.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::download_keys;
+/// use superkeyloader_lib::error::Error;
+/// use superkeyloader_lib::provider::ProviderOptions;
+///
+/// let options = ProviderOptions::default();
+/// let result = download_keys("nope:alice", "github", &options);
+///
+/// assert_eq!(result.identity, "nope:alice");
+/// assert!(matches!(result.keys, Err(Error::UnknownProvider(_))));
+/// ```
+///
+pub fn download_keys(
+    identity: &str,
+    default_provider: &str,
+    options: &ProviderOptions,
+) -> IdentityKeys {
+    let (prefix, username) = provider::split_identity(identity);
+    let provider_name = prefix.unwrap_or(default_provider);
+    let name = provider::canonical_identity(identity, default_provider);
+    // Keys URLs can hold secrets, they are never shown
+    let identity = match provider::resolve_name(provider_name) {
+        Some("url") => identity.replace(username, &url::public_url(username)),
+        _ => identity.to_string(),
+    };
+
+    let provider = match provider::from_name(provider_name, options) {
+        Some(provider) => provider,
+        None => {
+            return IdentityKeys {
+                identity,
+                name,
+                provider: String::new(),
+                keys: Err(Error::UnknownProvider(provider_name.to_string())),
+                rejected: Vec::new(),
+                scope: None,
             }
-            _ => Err(format!("API response code: {}", err)),
-        },
+        }
+    };
+
+    info!(
+        "Downloading keys for '{}' from {}...",
+        identity,
+        provider.display_name()
+    );
+
+    IdentityKeys {
+        identity,
+        name,
+        provider: provider.display_name().to_string(),
+        keys: error_handler_wrapper(provider.get_keys(username)),
+        rejected: Vec::new(),
+        scope: Some(provider.tag_scope(username)),
+    }
+}
+
+///
+/// Remove every key written for an identity, return removed lines
+///
+/// The identity managed block is removed, and so are its tagged keys outside of blocks (written by
+/// older versions) when the tag identifies the user (i.e. `from-LP-id-alice`). Tags with per key
+/// ids (i.e. GitHub ones) can't be tied to a user without downloading keys, so those keys are only
+/// reported (with a warning) and left untouched.
+///
+/// # Errors
+///
+/// Return `Error::UnknownProvider` if the identity prefix isn't a provider, and
+/// `Error::CorruptedBlock` if the identity block has no end marker.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+/// use superkeyloader_lib::provider::ProviderOptions;
+/// use superkeyloader_lib::revoke_keys;
+///
+/// let mut authorized_keys = AuthorizedKeys::parse(
+///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 me@laptop\n\
+///      ## BEGIN superkeyloader gh:alice\n\
+///      ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-1\n\
+///      ## END superkeyloader gh:alice\n",
+/// );
+/// let options = ProviderOptions::default();
+/// let removed = revoke_keys(&mut authorized_keys, "alice", "github", &options).unwrap();
+///
+/// assert_eq!(removed, vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA from-GH-id-1"]);
+/// assert_eq!(authorized_keys.lines().len(), 1);
+/// ```
+///
+pub fn revoke_keys(
+    authorized_keys: &mut AuthorizedKeys,
+    identity: &str,
+    default_provider: &str,
+    options: &ProviderOptions,
+) -> Result<Vec<String>> {
+    let (prefix, username) = provider::split_identity(identity);
+    let provider_name = prefix.unwrap_or(default_provider);
+    let provider = provider::from_name(provider_name, options)
+        .ok_or_else(|| Error::UnknownProvider(provider_name.to_string()))?;
+    let name = provider::canonical_identity(identity, default_provider);
+
+    let mut removed = authorized_keys.remove_block(&name)?;
+
+    let scope = provider.tag_scope(username);
+    if scope.attributable {
+        removed.extend(authorized_keys.remove_stale(&[scope], &[]));
+    } else {
+        let tagged = authorized_keys.tagged(&scope);
+        if !tagged.is_empty() {
+            warn!(
+                "{} keys tagged 'from-{}-id-' outside of managed blocks can't be tied to '{}', \
+                left untouched",
+                tagged.len(),
+                scope.source,
+                identity
+            );
+        }
+    }
+
+    Ok(removed)
+}
+
+///
+/// Merge the keys of all the successfully downloaded identities
+///
+/// Keys keep their download order, exact duplicates are written only once.
+///
+pub fn merge_keys(results: &[IdentityKeys]) -> Vec<SshPublicKey> {
+    let mut merged: Vec<SshPublicKey> = Vec::new();
+
+    for keys in results
+        .iter()
+        .filter_map(|result| result.keys.as_ref().ok())
+    {
+        for key in keys {
+            if !merged.contains(key) {
+                merged.push(key.clone());
+            }
+        }
+    }
+
+    merged
+}
+
+///
+/// Tag scopes that can be safely synced (see `AuthorizedKeys::remove_stale`)
+///
+/// Only scopes whose tags are tied to the identity (`TagScope::attributable`) are returned, GitHub
+/// style tags may belong to identities that aren't part of this run (see `take_legacy_keys`). An
+/// identity without keys upstream (`Error::NoKeys`) is synced too, so all its keys are removed.
+/// A scope is skipped if any identity in it failed for any other reason, a network error must
+/// never look like a removed key.
+///
+pub fn sync_scopes(results: &[IdentityKeys]) -> Vec<TagScope> {
+    let failed = failed_scopes(results);
+
+    let mut scopes: Vec<TagScope> = Vec::new();
+    for scope in results
+        .iter()
+        .filter_map(|result| result.scope.as_ref())
+        .filter(|scope| scope.attributable)
+    {
+        if !failed.contains(&scope) && !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+
+    scopes
+}
+
+///
+/// Tag scopes of the identities that failed for any reason but `Error::NoKeys`
+///
+fn failed_scopes(results: &[IdentityKeys]) -> Vec<&TagScope> {
+    results
+        .iter()
+        .filter(|result| match result.keys {
+            Ok(..) | Err(Error::NoKeys) => false,
+            Err(..) => true,
+        })
+        .filter_map(|result| result.scope.as_ref())
+        .collect()
+}
+
+///
+/// Remove the keys with shared tags (i.e. `from-GH-id-1`) outside of blocks that can be tied to
+/// a downloaded identity, so `update_block` writes them into its block
+///
+/// Such a key is taken only if exactly one identity of its scope has it upstream, and no identity
+/// of the scope failed. Return taken lines.
+///
+pub fn take_legacy_keys(
+    authorized_keys: &mut AuthorizedKeys,
+    results: &[IdentityKeys],
+) -> Vec<String> {
+    let failed = failed_scopes(results);
+    let mut taken = Vec::new();
+
+    for (index, result) in results.iter().enumerate() {
+        let (keys, scope) = match (&result.keys, &result.scope) {
+            (Ok(keys), Some(scope)) if !scope.attributable && !failed.contains(&scope) => {
+                (keys, scope)
+            }
+            _ => continue,
+        };
+        // Keys of other identities of the same scope
+        let others: Vec<&SshPublicKey> = results
+            .iter()
+            .enumerate()
+            .filter(|(other, result)| *other != index && result.scope.as_ref() == Some(scope))
+            .filter_map(|(_, result)| result.keys.as_ref().ok())
+            .flatten()
+            .collect();
+        let owned: Vec<String> = keys
+            .iter()
+            .filter(|key| !others.iter().any(|other| other.same_key(key)))
+            .map(ToString::to_string)
+            .collect();
+
+        taken.extend(authorized_keys.take_tagged(scope, &owned));
+    }
+
+    taken
+}
+
+///
+/// Keys outside of blocks tagged by the providers of `results` that can't be tied to an identity
+/// (i.e. `from-GH-id-1`), a sync leaves them in place
+///
+pub fn unattributed_keys(
+    authorized_keys: &AuthorizedKeys,
+    results: &[IdentityKeys],
+) -> Vec<String> {
+    let mut unattributed: Vec<String> = Vec::new();
+    for scope in results.iter().filter_map(|result| result.scope.as_ref()) {
+        if !scope.attributable {
+            for line in authorized_keys.tagged(scope) {
+                if !unattributed.contains(&line) {
+                    unattributed.push(line);
+                }
+            }
+        }
+    }
+    unattributed
+}
+
+///
+/// Write downloaded keys into `authorized_keys`, one managed block per identity
+///
+/// Identities that failed are left untouched. In sync mode blocks are replaced with the keys
+/// downloaded now, and tagged keys outside blocks that are gone upstream are removed when their
+/// tag ties them to a synced identity (see `sync_scopes`). Keys with shared tags are moved into
+/// the block of their identity when possible (see `take_legacy_keys`), the other ones are left
+/// in place and reported as `unattributed`.
+///
+/// # Errors
+///
+/// Return `Error::CorruptedBlock` if a managed block has no end marker.
+///
+pub fn update_authorized_keys(
+    authorized_keys: &mut AuthorizedKeys,
+    results: &[IdentityKeys],
+    sync: bool,
+) -> Result<Changes> {
+    let mut changes = Changes::default();
+
+    if sync {
+        let scopes = sync_scopes(results);
+        debug!("Syncing keys tagged by: {:?}", scopes);
+        let upstream: Vec<String> = merge_keys(results)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        changes.removed = authorized_keys.remove_stale(&scopes, &upstream);
+        changes.moved = take_legacy_keys(authorized_keys, results);
+    }
+
+    for result in results {
+        let keys: Vec<String> = match &result.keys {
+            Ok(keys) => keys.iter().map(ToString::to_string).collect(),
+            Err(Error::NoKeys) if sync => Vec::new(),
+            Err(..) => continue,
+        };
+        changes.extend(authorized_keys.update_block(&result.name, &keys, sync)?);
+    }
+
+    if sync {
+        // Moved keys were already in the file, they aren't new
+        let moved: Vec<KeyLine> = changes
+            .moved
+            .iter()
+            .filter_map(|line| KeyLine::parse(line))
+            .collect();
+        let (kept, added): (Vec<String>, Vec<String>) =
+            changes
+                .added
+                .drain(..)
+                .partition(|line| match KeyLine::parse(line) {
+                    Some(key) => moved.iter().any(|moved| moved.same_key(&key)),
+                    None => false,
+                });
+        changes.added = added;
+        changes.present.extend(kept);
+        changes.unattributed = unattributed_keys(authorized_keys, results);
     }
+
+    Ok(changes)
 }
 
 //
 // Testing
 //
 
+#[cfg(test)]
+fn test_key(line: &str) -> SshPublicKey {
+    SshPublicKey::parse(line).unwrap()
+}
+
 #[test]
 fn test_error_handling() {
     // All Ok
-    let all_ok_input: Result<Vec<String>, u16> = Ok(vec!["key1".to_string(), "key2".to_string()]);
-    let all_ok_output: Result<Vec<String>, failure::Error> =
-        Ok(vec!["key1".to_string(), "key2".to_string()]);
-    assert_eq!(
-        error_handler_wrapper(all_ok_input).unwrap(),
-        all_ok_output.unwrap()
-    );
+    let all_ok_input: Result<Vec<String>> = Ok(vec!["key1".to_string(), "key2".to_string()]);
+    let all_ok_output: Vec<String> = vec!["key1".to_string(), "key2".to_string()];
+    assert_eq!(error_handler_wrapper(all_ok_input).unwrap(), all_ok_output);
 
     // No keys
-    let no_keys_input: Result<Vec<String>, u16> = Ok(vec![]);
-    assert_eq!(error_handler_wrapper(no_keys_input).is_err(), true);
+    let no_keys_input: Result<Vec<String>> = Ok(vec![]);
+    assert!(matches!(
+        error_handler_wrapper(no_keys_input),
+        Err(Error::NoKeys)
+    ));
 
     // No user
-    let no_user_error_code: u16 = 404;
-    let no_user_input: Result<Vec<String>, u16> = Err(no_user_error_code);
-    assert_eq!(error_handler_wrapper(no_user_input).is_err(), true);
+    let no_user_input: Result<Vec<String>> = Err(Error::NotFound("erruser".into()));
+    assert!(matches!(
+        error_handler_wrapper(no_user_input),
+        Err(Error::NotFound(_))
+    ));
 
     // Other error
-    let other_error_code: u16 = 500;
-    let other_error_input: Result<Vec<String>, u16> = Err(other_error_code);
-    assert_eq!(error_handler_wrapper(other_error_input).is_err(), true);
+    let other_error_input: Result<Vec<String>> = Err(Error::UnexpectedStatus(500));
+    let other_error_output = error_handler_wrapper(other_error_input);
+    assert!(other_error_output
+        .unwrap_err()
+        .to_string()
+        .contains("API response code: 500"));
+
+    // Invalid username
+    let invalid_user_input: Result<Vec<String>> = Err(Error::InvalidUsername("user-".into()));
+    assert!(error_handler_wrapper(invalid_user_input)
+        .unwrap_err()
+        .to_string()
+        .contains("Invalid username"));
+
+    // Invalid API Response
+    let invalid_response_input: Result<Vec<String>> =
+        Err(Error::MalformedResponse("expected a sequence".into()));
+    assert!(matches!(
+        error_handler_wrapper(invalid_response_input),
+        Err(Error::MalformedResponse(_))
+    ));
+}
+
+#[test]
+fn test_merge_keys() {
+    use ssh_key::test_values::*;
+
+    let results = vec![
+        IdentityKeys {
+            identity: "gh:alice".into(),
+            name: "gh:alice".into(),
+            provider: "GitHub".into(),
+            keys: Ok(vec![test_key(ED25519_KEY), test_key(RSA_2048_KEY)]),
+            rejected: Vec::new(),
+            scope: Some(TagScope::new("GH", None)),
+        },
+        IdentityKeys {
+            identity: "gl:bob".into(),
+            name: "gl:bob".into(),
+            provider: "GitLab".into(),
+            keys: Err(Error::NotFound("bob".into())),
+            rejected: Vec::new(),
+            scope: Some(TagScope::new("GL", None)),
+        },
+        IdentityKeys {
+            identity: "gl:alice".into(),
+            name: "gl:alice".into(),
+            provider: "GitLab".into(),
+            keys: Ok(vec![test_key(RSA_2048_KEY), test_key(ECDSA_256_KEY)]),
+            rejected: Vec::new(),
+            scope: Some(TagScope::new("GL", None)),
+        },
+    ];
+
+    assert_eq!(
+        merge_keys(&results),
+        vec![
+            test_key(ED25519_KEY),
+            test_key(RSA_2048_KEY),
+            test_key(ECDSA_256_KEY)
+        ]
+    );
+}
+
+#[test]
+fn test_sync_scopes() {
+    let identity = |keys, scope| IdentityKeys {
+        identity: String::new(),
+        name: String::new(),
+        provider: String::new(),
+        keys,
+        rejected: Vec::new(),
+        scope,
+    };
+    let results = vec![
+        identity(
+            Ok(vec![test_key(ssh_key::test_values::ED25519_KEY)]),
+            Some(TagScope::new("GH", None)),
+        ),
+        identity(
+            Err(Error::NotFound("bob".into())),
+            Some(TagScope::new("GL", None)),
+        ),
+        identity(
+            Ok(vec![test_key(ssh_key::test_values::RSA_2048_KEY)]),
+            Some(TagScope::new("GL", None)),
+        ),
+        identity(Err(Error::NoKeys), Some(TagScope::new("LP", Some("carol")))),
+        identity(
+            Ok(vec![test_key(ssh_key::test_values::ECDSA_256_KEY)]),
+            Some(TagScope::new("GH", None)),
+        ),
+        identity(Err(Error::UnknownProvider("nope".into())), None),
+        identity(
+            Ok(vec![test_key(ssh_key::test_values::ED25519_KEY)]),
+            Some(TagScope::single_identity("https://keys.example/carol")),
+        ),
+    ];
+
+    // GitHub and GitLab tags can't be tied to the synced identities
+    assert_eq!(
+        sync_scopes(&results),
+        vec![
+            TagScope::new("LP", Some("carol")),
+            TagScope::single_identity("https://keys.example/carol")
+        ]
+    );
+}
+
+#[test]
+fn test_update_authorized_keys() {
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg";
+
+    let identity = |name: &str, keys| IdentityKeys {
+        identity: name.into(),
+        name: name.into(),
+        provider: String::new(),
+        keys,
+        rejected: Vec::new(),
+        scope: Some(TagScope::new("GH", None)),
+    };
+    let results = vec![
+        identity(
+            "gh:alice",
+            Ok(vec![test_key(&format!("{} from-GH-id-1", KEY))]),
+        ),
+        identity("gh:bob", Err(Error::NoKeys)),
+    ];
+    let content = format!(
+        "# BEGIN superkeyloader gh:bob\n{key}AAAA from-GH-id-2\n# END superkeyloader gh:bob\n\
+         {key}BBBB from-GH-id-3\n",
+        key = KEY
+    );
+
+    // Without sync 'gh:bob' block is left as it is
+    let mut authorized_keys = AuthorizedKeys::parse(&content);
+    let changes = update_authorized_keys(&mut authorized_keys, &results, false).unwrap();
+    assert_eq!(changes.added.len(), 1);
+    assert!(changes.removed.is_empty());
+    assert_eq!(authorized_keys.lines().len(), 7);
+
+    // With sync 'gh:bob' block is removed, the legacy GitHub key (maybe someone else's) is kept
+    let changes = update_authorized_keys(&mut authorized_keys, &results, true).unwrap();
+    assert!(changes.added.is_empty());
+    assert_eq!(changes.present.len(), 1);
+    assert_eq!(changes.removed.len(), 1);
+    assert_eq!(authorized_keys.lines().len(), 4);
+    assert!(authorized_keys.lines()[0].ends_with("from-GH-id-3"));
+}
+
+#[test]
+fn test_take_legacy_keys() {
+    use ssh_key::test_values::*;
+
+    let identity = |name: &str, keys| IdentityKeys {
+        identity: name.into(),
+        name: name.into(),
+        provider: String::new(),
+        keys,
+        rejected: Vec::new(),
+        scope: Some(TagScope::new("GH", None)),
+    };
+    let content = format!(
+        "{} from-GH-id-1\n{} from-GH-id-2\n{} from-GH-id-3\n",
+        ED25519_KEY, ECDSA_256_KEY, RSA_2048_KEY
+    );
+
+    // The ECDSA key belongs to both users, the RSA key to nobody of this run
+    let results = vec![
+        identity(
+            "gh:alice",
+            Ok(vec![test_key(ED25519_KEY), test_key(ECDSA_256_KEY)]),
+        ),
+        identity("gh:bob", Ok(vec![test_key(ECDSA_256_KEY)])),
+    ];
+    let mut authorized_keys = AuthorizedKeys::parse(&content);
+    let changes = update_authorized_keys(&mut authorized_keys, &results, true).unwrap();
+    assert_eq!(changes.moved, vec![format!("{} from-GH-id-1", ED25519_KEY)]);
+    assert!(changes.added.is_empty());
+    assert_eq!(changes.unattributed.len(), 2);
+    assert_eq!(authorized_keys.block("gh:alice").unwrap().len(), 1);
+
+    // Nothing is moved if an identity of the provider failed
+    let results = vec![
+        identity("gh:alice", Ok(vec![test_key(ED25519_KEY)])),
+        identity("gh:bob", Err(Error::UnexpectedStatus(500))),
+    ];
+    let mut authorized_keys = AuthorizedKeys::parse(&content);
+    assert!(take_legacy_keys(&mut authorized_keys, &results).is_empty());
+    assert_eq!(authorized_keys.lines().len(), 3);
+}
+
+#[test]
+fn test_revoke_keys() {
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg";
+
+    let content = format!(
+        "# BEGIN superkeyloader lp:alice\n{key} from-LP-id-alice\n# END superkeyloader lp:alice\n\
+         {key}AAAA from-LP-id-alice\n{key}BBBB from-LP-id-bob\n{key}CCCC from-GH-id-1\n",
+        key = KEY
+    );
+    let mut authorized_keys = AuthorizedKeys::parse(&content);
+    let options = ProviderOptions::default();
 
-    //
-    // GitHub Errors
-    //
+    let removed = revoke_keys(&mut authorized_keys, "lp:alice", "github", &options).unwrap();
+    assert_eq!(removed.len(), 2);
+    assert_eq!(authorized_keys.lines().len(), 2);
 
-    // Invalid GitHub username
-    let invalid_user_input: Result<Vec<String>, u16> = Err(gh::INVALID_GH_USERNAME);
-    assert_eq!(error_handler_wrapper(invalid_user_input).is_err(), true);
+    // GitHub tags can't be tied to a user outside of blocks
+    let removed = revoke_keys(&mut authorized_keys, "bob", "github", &options).unwrap();
+    assert!(removed.is_empty());
 
-    // Invalid GitHub API Response
-    let invalid_user_input: Result<Vec<String>, u16> = Err(gh::INVALID_GH_API_RESPONSE);
-    assert_eq!(error_handler_wrapper(invalid_user_input).is_err(), true);
+    assert!(matches!(
+        revoke_keys(&mut authorized_keys, "nope:alice", "github", &options),
+        Err(Error::UnknownProvider(_))
+    ));
 }
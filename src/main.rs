@@ -7,20 +7,38 @@ extern crate pretty_env_logger;
 use atty::Stream;
 use human_panic::setup_panic;
 use serde_json::json;
-use shellexpand;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
+use std::path::Path;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+use superkeyloader_lib::authorized_keys::AuthorizedKeys;
+use superkeyloader_lib::ssh_key::SshPublicKey;
 use superkeyloader_lib::*;
 
 //
 // CLI Arguments parsing struct
 //
+// Identities and subcommands are mutually exclusive, so an identity named like a
+// subcommand must be prefixed (i.e. 'gh:restore')
+//
 #[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::SubcommandsNegateReqs)]
 struct CliArgs {
-    // Required argument. GitHub username.
-    username: String,
+    // Required argument. One or more usernames, optionally prefixed by the provider
+    // name or alias (i.e. 'gh:alice', 'sourcehut:bob'), or URLs of a keys file
+    #[structopt(required = true, min_values = 1)]
+    identities: Vec<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    // Key provider used for usernames without a prefix (defaults to GitHub)
+    #[structopt(
+        long = "provider",
+        default_value = "github",
+        possible_values = provider::PROVIDERS
+    )]
+    provider: String,
 
     // Optional output file (if you need a to append keys to a file other than
     // '~/.ssh/authorized_keys')
@@ -28,30 +46,209 @@ struct CliArgs {
         short = "o",
         long = "output",
         required = false,
+        global = true,
         default_value = "~/.ssh/authorized_keys",
         parse(from_os_str)
     )]
     path: std::path::PathBuf,
 
-    // Optional GitHub API token (use if you reach API rate limits)
-    // Acutally used only during testing on CI to overcome API rate limits
-    #[structopt(long = "token")]
-    token: Option<String>,
+    // Number of 'authorized_keys' backups to keep, saved next to it before every change
+    // ('0' disables backups)
+    #[structopt(long = "backups", global = true, default_value = "5")]
+    backups: usize,
+
+    // Optional API tokens (use if you reach API rate limits or for private instances), each one
+    // is sent only to its own provider. '--token' is the old name of '--github-token'
+    // Acutally used only during testing on CI to overcome GitHub API rate limits
+    #[structopt(
+        long = "github-token",
+        alias = "token",
+        env = "SUPERKEYLOADER_GITHUB_TOKEN",
+        hide_env_values = true
+    )]
+    github_token: Option<String>,
+
+    #[structopt(
+        long = "gitlab-token",
+        env = "SUPERKEYLOADER_GITLAB_TOKEN",
+        hide_env_values = true
+    )]
+    gitlab_token: Option<String>,
+
+    // Bitbucket app password, in the '<username>:<app password>' format
+    #[structopt(
+        long = "bitbucket-app-password",
+        env = "SUPERKEYLOADER_BITBUCKET_APP_PASSWORD",
+        hide_env_values = true
+    )]
+    bitbucket_app_password: Option<String>,
+
+    #[structopt(
+        long = "gitea-token",
+        env = "SUPERKEYLOADER_GITEA_TOKEN",
+        hide_env_values = true
+    )]
+    gitea_token: Option<String>,
+
+    // GitHub API base URL (for GitHub Enterprise Server use 'https://<host>/api/v3')
+    #[structopt(
+        long = "github-api-url",
+        env = "GITHUB_API_URL",
+        default_value = github::DEFAULT_BASE_URL
+    )]
+    github_url: String,
+
+    // GitLab instance base URL (for self-hosted instances)
+    #[structopt(long = "gitlab-url", default_value = gitlab::DEFAULT_BASE_URL)]
+    gitlab_url: String,
+
+    // Gitea/Forgejo instance base URL (defaults to Codeberg)
+    #[structopt(long = "gitea-url", default_value = gitea::DEFAULT_BASE_URL)]
+    gitea_url: String,
+
+    // Accept plain HTTP key URLs (keys could be tampered with on the network, prefer HTTPS)
+    #[structopt(long = "allow-http")]
+    allow_http: bool,
+
+    // Remove keys written by superkeyloader that were deleted upstream: managed blocks of the
+    // given identities, and tagged keys outside of blocks only if the tag names the identity
+    // (i.e. 'from-LP-id-alice'). Hand-written keys are never touched
+    #[structopt(long = "sync")]
+    sync: bool,
+
+    // Download keys and compute the changes without writing anything, print a unified
+    // diff of 'authorized_keys' file (or the list of changed lines with '--json')
+    // 'prune' and 'revoke' only print the keys they would remove
+    #[structopt(long = "dry-run", global = true)]
+    dry_run: bool,
+
+    // Key algorithms allowed, comma separated (every known algorithm except 'ssh-dss' if not set)
+    #[structopt(
+        long = "allowed-algorithms",
+        env = "SUPERKEYLOADER_ALLOWED_ALGORITHMS",
+        use_delimiter = true,
+        possible_values = provider::KEY_TYPES
+    )]
+    allowed_algorithms: Vec<String>,
+
+    // Minimum RSA key size in bits, smaller keys are rejected
+    #[structopt(
+        long = "min-rsa-bits",
+        env = "SUPERKEYLOADER_MIN_RSA_BITS",
+        default_value = "3072"
+    )]
+    min_rsa_bits: usize,
+
+    // Allow only keys backed by a FIDO security key ('sk-ssh-ed25519@openssh.com', etc.)
+    #[structopt(long = "require-security-key")]
+    require_security_key: bool,
+
+    // 'authorized_keys' options of every imported key, comma separated and validated
+    // (i.e. 'restrict,from="10.0.0.0/8",expiry-time="20261231"')
+    #[structopt(long = "key-options", env = "SUPERKEYLOADER_KEY_OPTIONS")]
+    key_options: Option<key_options::KeyOptions>,
+
+    // Options of the keys of a single identity, used instead of '--key-options'
+    // (i.e. '--identity-options gh:alice no-pty'), can be passed many times
+    #[structopt(
+        long = "identity-options",
+        number_of_values = 2,
+        value_names = &["identity", "options"]
+    )]
+    identity_options: Vec<String>,
+
+    // Grant access only for a while (i.e. '8h', '7d'): keys get the 'expiry-time' option and
+    // a 'grant-until-<TIME>' tag, expired keys are removed by 'prune'
+    #[structopt(long = "for", parse(try_from_str = grant::parse_duration))]
+    grant: Option<u64>,
+
+    // Show legacy MD5 fingerprints too (SHA256 fingerprints are always shown)
+    #[structopt(long = "md5", conflicts_with = "stdout")]
+    md5: bool,
 
     // Enable setting verbosity level with '--verbose', '-v', '-vv', etc. flags
     #[structopt(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 
-    #[structopt(short = "m", long = "human", conflicts_with_all(&["json", "stdout"]))]
+    #[structopt(
+        short = "m",
+        long = "human",
+        global = true,
+        conflicts_with_all(&["json", "stdout"])
+    )]
     human: bool,
 
-    #[structopt(short = "j", long = "json", conflicts_with_all(&["human", "stdout"]))]
+    #[structopt(
+        short = "j",
+        long = "json",
+        global = true,
+        conflicts_with_all(&["human", "stdout"])
+    )]
     json: bool,
 
-    #[structopt(short = "p", long = "stdout", conflicts_with_all(&["human", "json"]))]
+    // Print downloaded keys in 'authorized_keys' format to STDOUT, no file is read or written
+    // (i.e. 'superkeyloader -p alice | ssh-keygen -lf -')
+    #[structopt(
+        short = "p",
+        long = "stdout",
+        conflicts_with_all(&["human", "json", "sync", "dry-run"])
+    )]
     stdout: bool,
 }
 
+// 'check' exit codes, added together for many kinds of drift (i.e. 6 for missing and stale keys)
+const CHECK_MISSING: i32 = 2;
+const CHECK_STALE: i32 = 4;
+const CHECK_UNMANAGED: i32 = 8;
+
+//
+// Subcommands, used instead of identities
+//
+#[derive(Debug, StructOpt)]
+enum Command {
+    // Restore 'authorized_keys' file from a backup (the latest one if no backup is given)
+    #[structopt(name = "restore")]
+    Restore {
+        // List available backups, newest first
+        #[structopt(long = "list", conflicts_with = "backup")]
+        list: bool,
+
+        // Backup file name (as listed by '--list')
+        backup: Option<String>,
+    },
+
+    // Remove keys whose time-limited grant ('--for') expired, for sshd versions that don't
+    // support the 'expiry-time' option
+    #[structopt(name = "prune")]
+    Prune,
+
+    // List the keys of 'authorized_keys' file grouped by origin (identity block, origin tag
+    // or unmanaged), with size, fingerprint, options and comment
+    #[structopt(name = "list")]
+    List,
+
+    // Remove every key written for the identities: their managed blocks, and their tagged
+    // keys outside of blocks if the tag identifies the user (i.e. 'from-LP-id-alice')
+    #[structopt(name = "revoke")]
+    Revoke {
+        // Identities to revoke, like the ones keys were imported for (i.e. 'gh:alice')
+        #[structopt(required = true, min_values = 1)]
+        identities: Vec<String>,
+    },
+
+    // Download keys and compare them with 'authorized_keys' file, without writing it. Exit code
+    // is 0 if in sync, otherwise the sum of 2 (missing keys), 4 (stale or outdated keys) and
+    // 8 (unmanaged keys, or tagged keys not tied to an identity), or 1 on errors
+    #[structopt(name = "check")]
+    Check {
+        // Identities to check (i.e. 'gh:alice')
+        #[structopt(required = true, min_values = 1)]
+        identities: Vec<String>,
+    },
+}
+
+// 'human-panic' macro still uses the deprecated 'PanicInfo' type alias
+#[allow(deprecated)]
 fn main() -> Result<(), ExitDisplay<String>> {
     // Enable human-friendly panic message
     setup_panic!();
@@ -78,87 +275,928 @@ fn main() -> Result<(), ExitDisplay<String>> {
         .init();
 
     info!("Human: {} - JSON: {}", &args.human, &args.json);
+
+    let args_path_string = args.path.to_str().unwrap();
+
+    let authorized_keys_path = shellexpand::tilde(args_path_string).to_string();
+
+    info!("Got 'authorized_keys' file path: {}", authorized_keys_path);
+
+    let is_tty = atty::is(Stream::Stdout);
+
+    // Command line flags have precedence, if no flag is set, then
+    //  if command is executed in an interactive terminal will output
+    //  a human message, else it will output JSON
+    let human_output = !args.json && is_tty || args.human;
+
+    if args.command.is_some() && !args.identities.is_empty() {
+        return Err("Identities and subcommands can't be used together \
+            (identities named like a subcommand must be prefixed, i.e. 'gh:restore')"
+            .to_string()
+            .into());
+    }
+
+    if let Some(command) = &args.command {
+        let path = Path::new(&authorized_keys_path);
+        let output = match command {
+            Command::Restore { .. } | Command::List if args.dry_run => {
+                return Err("'restore' and 'list' don't support '--dry-run'"
+                    .to_string()
+                    .into())
+            }
+            Command::Restore { list, backup } => {
+                restore(path, *list, backup.as_deref(), args.backups, human_output)?
+            }
+            Command::Prune => prune(path, &args, human_output)?,
+            Command::List => list(path, args.md5, human_output)?,
+            Command::Revoke { identities } => revoke(path, identities, &args, human_output)?,
+            Command::Check { .. } if args.grant.is_some() => {
+                return Err("'check' doesn't support '--for'".to_string().into())
+            }
+            Command::Check { identities } => {
+                let (output, code) = check(path, identities, &args, human_output)?;
+                if !args.verbose.is_silent() {
+                    println!("{}", output);
+                }
+                std::process::exit(code);
+            }
+        };
+        if !args.verbose.is_silent() {
+            println!("{}", output);
+        }
+        return Ok(());
+    }
+
+    let mut results = fetch_keys(&args.identities, &args)?;
+
     //
-    // Download keys and build a vector of key strings
-    // and handling connection and "availability" errors
+    // Limit keys in time, the grant is recorded in the key comment
     //
-    info!("Downloading keys for '{}' from GitHub...", &args.username);
+    let grant_until = args.grant.map(|duration| time::now() + duration);
+    if let Some(until) = grant_until {
+        for result in &mut results {
+            result.apply_grant(until);
+        }
+    }
 
-    let keys = error_handler_wrapper(gh::get_keys(&args.username, args.token))?;
+    let keys = merge_keys(&results);
     let keys_number = keys.len();
 
-    info!("Downloaded {} keys.", keys_number);
+    //
+    // IF output is 'stdout' THEN print only the keys, one per line, and leave
+    // 'authorized_keys' file alone. Errors are still reported on STDERR.
+    //
+    if args.stdout {
+        for key in &keys {
+            println!("{}", key);
+        }
+        return identity_errors(&results);
+    }
 
     //
-    // Create 'authorized_keys' file if not exists and access it in 'append mode'.
-    // (if testing, will use a local file)
+    // Read the current 'authorized_keys' file (if any), keys already present in it are skipped
+    // comparing only key type and key data (options and comments are ignored).
     //
+    let mut authorized_keys = match AuthorizedKeys::read(Path::new(&authorized_keys_path)) {
+        Ok(authorized_keys) => authorized_keys,
+        Err(why) => {
+            return Err(format!("Error reading 'authorized_keys' file. Caused by {}", why).into())
+        }
+    };
 
-    let args_path_string = args.path.to_str().unwrap();
+    let original_lines = authorized_keys.lines().to_vec();
 
-    let authorized_keys_path = shellexpand::tilde(args_path_string).to_owned().to_string();
+    //
+    // Write keys of every identity into its managed block. In sync mode also remove managed
+    // keys that are gone upstream, only for providers (and users) whose keys were all
+    // downloaded successfully.
+    //
+    let changes = match update_authorized_keys(&mut authorized_keys, &results, args.sync) {
+        Ok(changes) => changes,
+        Err(why) => return Err(why.to_string().into()),
+    };
+    let added_keys = &changes.added;
+    let present_keys = &changes.present;
+    let removed_keys = &changes.removed;
+    let added_number = added_keys.len();
+    let updated_number = changes.updated.len();
+    let removed_number = removed_keys.len();
 
-    info!("Got 'authorized_keys' file path: {}", authorized_keys_path);
+    info!(
+        "{} new keys, {} keys already present, {} keys updated, {} keys removed",
+        added_number,
+        present_keys.len(),
+        updated_number,
+        removed_number
+    );
 
-    let authorized_keys_file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(authorized_keys_path)
-        .unwrap();
+    for (i, key) in added_keys.iter().enumerate() {
+        debug!(
+            "Writing key {}/{} ({})",
+            i + 1,
+            added_number,
+            line_fingerprint(key)
+        );
+    }
+    for key in &changes.updated {
+        info!("Updating key {}: {}", line_fingerprint(key), key);
+    }
+    for key in removed_keys {
+        info!("Removing key {}: {}", line_fingerprint(key), key);
+    }
+    for key in &changes.moved {
+        info!(
+            "Moving key {} into its managed block",
+            line_fingerprint(key)
+        );
+    }
+    for key in &changes.unattributed {
+        warn!(
+            "Key {} can't be tied to an identity, left in place: {}",
+            line_fingerprint(key),
+            key
+        );
+    }
 
-    info!("Opened/Created 'authorized_keys' file in append mode");
+    //
+    // Create 'authorized_keys' file (and its directory) if not exists and atomically
+    // write it back, only if something changed. The current file is backed up first.
+    // (if testing, will use a local file)
+    //
+    if args.dry_run {
+        info!("Dry run, 'authorized_keys' file not written");
+    } else if !changes.is_empty() {
+        if let Err(why) = backup::create(Path::new(&authorized_keys_path), args.backups) {
+            return Err(format!(
+                "Error saving a backup of 'authorized_keys' file, nothing changed. Caused by {}",
+                why
+            )
+            .into());
+        }
 
-    for (i, key) in keys.iter().enumerate() {
-        match writeln!(&authorized_keys_file, "{}", key) {
-            Ok(..) => {
-                // TODO: Use something safer than substring (like a functional 'truncate').
-                //       It will panics if 'key' is less than 16 chars.
-                debug!("Wrote key {}/{} ({}...)", i, keys_number, &key[..48]);
-            }
-            Err(why) => {
-                return Err(format!(
-                    "Error writing key {}/{} to 'authorized_keys' file. Caused by {}",
-                    i, keys_number, why
-                )
-                .into());
-            }
-        };
+        if let Err(why) = authorized_keys.write(Path::new(&authorized_keys_path)) {
+            return Err(format!(
+                "Error writing {} keys to 'authorized_keys' file. Caused by {}",
+                added_number, why
+            )
+            .into());
+        }
+
+        info!("Written 'authorized_keys' file");
     }
 
     //
     // IF output is 'interactive' THEN prints a simple summary message.
-    // IF output is 'non-interactive' THEN print a JSON that contains the downloaded keys.
+    // IF output is 'non-interactive' THEN print a JSON that contains the downloaded keys
+    // and the result of every identity.
     // i.e.:
     //
     // {
     //   "keys": [
-    //     "ssh-rsa AAAAB3NzaC1yc2EAAAAD...",
-    //     "ssh-rsa AAAAB3NzaC1yc2EAAAAD..."
+    //     { "key": "ssh-rsa AAAAB3NzaC1yc2EAAAAD...", "fingerprint": "SHA256:2Jw5k..." },
+    //     { "key": "ssh-rsa AAAAB3NzaC1yc2EAAAAD...", "fingerprint": "SHA256:uB3Tp..." }
+    //   ],
+    //   "added": 1,
+    //   "present": 1,
+    //   "updated": 0,
+    //   "removed": 0,
+    //   "identities": [
+    //     { "identity": "gh:alice", "provider": "GitHub", "keys": 2, "rejected": [] },
+    //     { "identity": "gl:bob", "provider": "GitLab", "error": "Wrong username, ..." }
     //   ]
     // }
     //
-    let output: String;
+    // In sync mode the number of keys written by older versions moved into their block is added
+    // ('"moved": 1'), and the ones left in place because they can't be tied to an identity
+    // ('"unattributed": [{ "key": "...", "fingerprint": "..." }]').
+    //
+    // A time-limited grant ('--for') adds its expiry time, i.e. '"grant_until": "20261231T235959Z"'.
+    //
+    // A dry run prints the unified diff of 'authorized_keys' file instead, or adds to the
+    // JSON '"dry_run": true' and the changed lines, i.e.:
+    //
+    //   "changes": [ { "action": "add", "line": "ssh-rsa AAAAB3NzaC1yc2EAAAAD..." } ]
+    //
+    let output = if human_output {
+        let mut lines: Vec<String> = Vec::new();
+        for result in &results {
+            if let Ok(keys) = &result.keys {
+                lines.push(format!(
+                    "Downloaded {} SSH keys for user '{}' from {}.",
+                    keys.len() + result.rejected.len(),
+                    result.identity,
+                    result.provider
+                ));
+                // Like 'ssh-keygen -l' output
+                lines.extend(
+                    keys.iter()
+                        .map(|key| format!("  {}", describe(key, args.md5))),
+                );
+                lines.extend(result.rejected.iter().map(|rejected| {
+                    format!(
+                        "  Rejected {}: {}",
+                        describe(&rejected.key, args.md5),
+                        rejected.reason
+                    )
+                }));
+            }
+        }
+        if args.dry_run {
+            let diff = diff::unified_diff(
+                &original_lines,
+                authorized_keys.lines(),
+                &authorized_keys_path,
+                &authorized_keys_path,
+            );
+            if !diff.is_empty() {
+                lines.push(diff.trim_end().to_string());
+            }
+            lines.push(format!(
+                "Dry run, 'authorized_keys' file not changed: {} SSH keys would be added, {} removed, {} kept.",
+                added_number,
+                removed_number,
+                present_keys.len()
+            ));
+        } else if args.sync {
+            lines.push(format!(
+                "Synced 'authorized_keys' file: {} SSH keys added, {} removed, {} kept.",
+                added_number,
+                removed_number,
+                present_keys.len()
+            ));
+        } else if keys_number > 0 {
+            lines.push(format!(
+                "Appended {} SSH keys to 'authorized_keys' file ({} already present).",
+                added_number,
+                present_keys.len()
+            ));
+        }
+        if !changes.moved.is_empty() {
+            lines.push(format!(
+                "{} SSH keys written by an older version {} into their managed block.",
+                changes.moved.len(),
+                if args.dry_run {
+                    "would be moved"
+                } else {
+                    "moved"
+                }
+            ));
+        }
+        if !changes.unattributed.is_empty() {
+            lines.push(format!(
+                "Warning: {} SSH keys written by an older version were left in place, they can't \
+                 be tied to an identity (remove them by hand if they were revoked upstream):",
+                changes.unattributed.len()
+            ));
+            lines.extend(changes.unattributed.iter().filter_map(|line| {
+                let key = SshPublicKey::parse(line).ok()?;
+                Some(format!("  {}", describe(&key, args.md5)))
+            }));
+        }
+        if updated_number > 0 {
+            lines.push(format!(
+                "{} SSH keys already present {} with new options.",
+                updated_number,
+                if args.dry_run {
+                    "would be rewritten"
+                } else {
+                    "rewritten"
+                }
+            ));
+        }
+        if let Some(until) = grant_until {
+            lines.push(format!(
+                "Access granted until {}, expired keys are removed by 'superkeyloader prune'.",
+                time::format_timestamp(until)
+            ));
+        }
+        lines.join("\n")
+    } else {
+        let identities: Vec<_> = results
+            .iter()
+            .map(|result| match &result.keys {
+                Ok(keys) => json!({
+                    "identity": result.identity,
+                    "provider": result.provider,
+                    "keys": keys.len(),
+                    "rejected": result
+                        .rejected
+                        .iter()
+                        .map(|rejected| json!({
+                            "key": rejected.key.to_string(),
+                            "fingerprint": rejected.key.fingerprint(),
+                            "reason": rejected.reason.to_string(),
+                        }))
+                        .collect::<Vec<_>>(),
+                }),
+                Err(why) => json!({
+                    "identity": result.identity,
+                    "provider": result.provider,
+                    "error": why.to_string(),
+                }),
+            })
+            .collect();
+        let mut output = json!({
+            "keys": keys
+                .iter()
+                .map(|key| {
+                    let mut entry = json!({
+                        "key": key.to_string(),
+                        "fingerprint": key.fingerprint(),
+                    });
+                    if args.md5 {
+                        entry["md5"] = json!(key.md5_fingerprint());
+                    }
+                    entry
+                })
+                .collect::<Vec<_>>(),
+            "added": added_number,
+            "present": present_keys.len(),
+            "updated": updated_number,
+            "removed": removed_number,
+            "identities": identities,
+        });
+        if args.sync {
+            output["moved"] = json!(changes.moved.len());
+            output["unattributed"] = json!(changes
+                .unattributed
+                .iter()
+                .map(|line| json!({ "key": line, "fingerprint": line_fingerprint(line) }))
+                .collect::<Vec<_>>());
+        }
+        if let Some(until) = grant_until {
+            output["grant_until"] = json!(time::format_timestamp(until));
+        }
+        if args.dry_run {
+            let lines: Vec<_> = diff::diff_lines(&original_lines, authorized_keys.lines())
+                .into_iter()
+                .filter_map(|change| match change {
+                    diff::Change::Added(line) => Some(json!({ "action": "add", "line": line })),
+                    diff::Change::Removed(line) => {
+                        Some(json!({ "action": "remove", "line": line }))
+                    }
+                    diff::Change::Equal(..) => None,
+                })
+                .collect();
+            output["dry_run"] = json!(true);
+            output["changes"] = json!(lines);
+        }
+        output.to_string()
+    };
 
-    let is_tty = atty::is(Stream::Stdout);
+    if !args.verbose.is_silent() && !output.is_empty() {
+        println!("{}", output);
+    }
 
-    // Command line flags have precedence, if no flag is set, then
-    //  if command is executed in an interactive terminal will output
-    //  a human message, else it will output JSON
-    let human_output = !args.json && is_tty || args.human;
+    //
+    // Exit with an error if at least one identity failed (keys of the other ones are
+    // already written)
+    //
+    identity_errors(&results)
+}
 
-    if human_output {
-        output = format!(
-            "Downloaded {} SSH keys for user '{}' \
-            from {} and appended to 'authorized_keys' file.",
-            keys_number, &args.username, "GitHub"
-        );
+//
+// Download the keys of the identities, then apply the key policy and the 'authorized_keys'
+// options (shared by the import and by 'check')
+//
+fn fetch_keys(
+    identities: &[String],
+    args: &CliArgs,
+) -> Result<Vec<IdentityKeys>, ExitDisplay<String>> {
+    //
+    // Validate per identity options before downloading anything, identities are matched by
+    // their canonical name (i.e. 'alice' and 'gh:alice' are the same)
+    //
+    let mut identity_options: Vec<(String, key_options::KeyOptions)> = Vec::new();
+    for pair in args.identity_options.chunks(2) {
+        let name = provider::canonical_identity(&pair[0], &args.provider);
+        if !identities
+            .iter()
+            .any(|identity| provider::canonical_identity(identity, &args.provider) == name)
+        {
+            return Err(format!(
+                "'--identity-options' given for '{}', that isn't one of the identities",
+                pair[0]
+            )
+            .into());
+        }
+        match key_options::KeyOptions::parse(&pair[1]) {
+            Ok(options) => identity_options.push((name, options)),
+            Err(why) => return Err(format!("Options of '{}': {}", pair[0], why).into()),
+        }
+    }
+
+    //
+    // Download keys and build a vector of key strings
+    // and handling connection and "availability" errors
+    //
+    let provider_options = provider::ProviderOptions {
+        github_token: args.github_token.clone(),
+        gitlab_token: args.gitlab_token.clone(),
+        bitbucket_app_password: args.bitbucket_app_password.clone(),
+        gitea_token: args.gitea_token.clone(),
+        github_url: args.github_url.clone(),
+        gitlab_url: args.gitlab_url.clone(),
+        gitea_url: args.gitea_url.clone(),
+        allow_http: args.allow_http,
+    };
+
+    let mut results: Vec<IdentityKeys> = identities
+        .iter()
+        .map(|identity| download_keys(identity, &args.provider, &provider_options))
+        .collect();
+
+    //
+    // Drop keys not allowed by the key policy before anything is written, rejected
+    // keys are reported with the reason
+    //
+    let mut policy = policy::KeyPolicy {
+        min_rsa_bits: args.min_rsa_bits,
+        require_security_key: args.require_security_key,
+        ..policy::KeyPolicy::default()
+    };
+    if !args.allowed_algorithms.is_empty() {
+        policy.allowed_algorithms = args.allowed_algorithms.clone();
+    }
+    debug!("Key policy: {:?}", policy);
+
+    for result in &mut results {
+        result.apply_policy(&policy);
+    }
+
+    //
+    // Set 'authorized_keys' options of the accepted keys
+    //
+    for result in &mut results {
+        let options = identity_options
+            .iter()
+            .find(|(name, _)| *name == result.name)
+            .map(|(_, options)| options)
+            .or(args.key_options.as_ref());
+        if let Some(options) = options {
+            debug!("Options of '{}' keys: {}", result.identity, options);
+            result.apply_options(options);
+        }
+    }
+
+    for result in &results {
+        match &result.keys {
+            Ok(keys) => info!("Downloaded {} keys for '{}'.", keys.len(), result.identity),
+            Err(why) => warn!("Could not download keys for '{}': {}", result.identity, why),
+        }
+    }
+
+    Ok(results)
+}
+
+//
+// SHA256 fingerprint of a key, followed by the MD5 one if requested
+//
+fn fingerprints(key: &SshPublicKey, md5: bool) -> String {
+    if md5 {
+        format!("{} {}", key.fingerprint(), key.md5_fingerprint())
     } else {
-        output = json!({ "keys": keys }).to_string();
+        key.fingerprint()
     }
+}
 
-    if !args.verbose.is_silent() {
-        println!("{}", output);
+//
+// Key fingerprints, comment and algorithm, like 'ssh-keygen -l' output
+//
+fn describe(key: &SshPublicKey, md5: bool) -> String {
+    format!(
+        "{} {} ({})",
+        fingerprints(key, md5),
+        key.comment().unwrap_or("no comment"),
+        key.algorithm()
+    )
+}
+
+//
+// Fingerprint of an 'authorized_keys' line, used in logs
+//
+fn line_fingerprint(line: &str) -> String {
+    match SshPublicKey::parse(line) {
+        Ok(key) => key.fingerprint(),
+        Err(..) => "no valid key".to_string(),
+    }
+}
+
+//
+// Join the errors of all the identities that failed, if any
+//
+fn identity_errors(results: &[IdentityKeys]) -> Result<(), ExitDisplay<String>> {
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|result| match &result.keys {
+            Ok(..) => None,
+            Err(why) => Some(format!("'{}': {}", result.identity, why)),
+        })
+        .collect();
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
     }
 
     Ok(())
 }
+
+//
+// 'restore' subcommand: list backups or restore one of them.
+// Return the output message (human or JSON)
+//
+fn restore(
+    path: &Path,
+    list: bool,
+    backup_name: Option<&str>,
+    retention: usize,
+    human_output: bool,
+) -> Result<String, ExitDisplay<String>> {
+    if list {
+        let backups: Vec<String> = match backup::list(path) {
+            Ok(backups) => backups
+                .iter()
+                .map(|backup| backup.display().to_string())
+                .collect(),
+            Err(why) => return Err(format!("Error listing backups. Caused by {}", why).into()),
+        };
+        return Ok(if human_output {
+            backups.join("\n")
+        } else {
+            json!({ "backups": backups }).to_string()
+        });
+    }
+
+    let backup = match backup::find(path, backup_name) {
+        Ok(backup) => backup,
+        Err(why) => return Err(why.to_string().into()),
+    };
+    if let Err(why) = backup::restore(path, &backup, retention) {
+        return Err(format!(
+            "Error restoring 'authorized_keys' file from '{}'. Caused by {}",
+            backup.display(),
+            why
+        )
+        .into());
+    }
+
+    Ok(if human_output {
+        format!(
+            "Restored 'authorized_keys' file from '{}'.",
+            backup.display()
+        )
+    } else {
+        json!({ "restored": backup.display().to_string() }).to_string()
+    })
+}
+
+//
+// Save a backup of 'authorized_keys' file and write the new content
+//
+fn write_with_backup(
+    path: &Path,
+    authorized_keys: &AuthorizedKeys,
+    retention: usize,
+) -> Result<(), ExitDisplay<String>> {
+    if let Err(why) = backup::create(path, retention) {
+        return Err(format!(
+            "Error saving a backup of 'authorized_keys' file, nothing changed. Caused by {}",
+            why
+        )
+        .into());
+    }
+    if let Err(why) = authorized_keys.write(path) {
+        return Err(format!("Error writing 'authorized_keys' file. Caused by {}", why).into());
+    }
+
+    info!("Written 'authorized_keys' file");
+    Ok(())
+}
+
+//
+// 'prune' subcommand: remove keys whose grant expired.
+// Return the output message (human or JSON)
+//
+fn prune(path: &Path, args: &CliArgs, human_output: bool) -> Result<String, ExitDisplay<String>> {
+    let mut authorized_keys = match AuthorizedKeys::read(path) {
+        Ok(authorized_keys) => authorized_keys,
+        Err(why) => {
+            return Err(format!("Error reading 'authorized_keys' file. Caused by {}", why).into())
+        }
+    };
+
+    let pruned = match authorized_keys.prune_expired(time::now()) {
+        Ok(pruned) => pruned,
+        Err(why) => return Err(why.to_string().into()),
+    };
+    for line in &pruned {
+        info!("Removing expired key {}: {}", line_fingerprint(line), line);
+    }
+
+    if !pruned.is_empty() && !args.dry_run {
+        write_with_backup(path, &authorized_keys, args.backups)?;
+    }
+
+    Ok(if human_output {
+        let mut lines = vec![if args.dry_run {
+            format!(
+                "Dry run, 'authorized_keys' file not changed: {} expired SSH keys would be pruned.",
+                pruned.len()
+            )
+        } else {
+            format!(
+                "Pruned {} expired SSH keys from 'authorized_keys' file.",
+                pruned.len()
+            )
+        }];
+        lines.extend(pruned.iter().filter_map(|line| {
+            let key = SshPublicKey::parse(line).ok()?;
+            Some(format!("  {}", describe(&key, args.md5)))
+        }));
+        lines.join("\n")
+    } else {
+        let mut output = json!({ "pruned": pruned });
+        if args.dry_run {
+            output["dry_run"] = json!(true);
+        }
+        output.to_string()
+    })
+}
+
+//
+// 'revoke' subcommand: remove every key written for the identities.
+// Return the output message (human or JSON), with the fingerprints of removed keys
+//
+fn revoke(
+    path: &Path,
+    identities: &[String],
+    args: &CliArgs,
+    human_output: bool,
+) -> Result<String, ExitDisplay<String>> {
+    let mut authorized_keys = match AuthorizedKeys::read(path) {
+        Ok(authorized_keys) => authorized_keys,
+        Err(why) => {
+            return Err(format!("Error reading 'authorized_keys' file. Caused by {}", why).into())
+        }
+    };
+    // Nothing is downloaded, so no credentials
+    let provider_options = provider::ProviderOptions {
+        github_url: args.github_url.clone(),
+        gitlab_url: args.gitlab_url.clone(),
+        gitea_url: args.gitea_url.clone(),
+        ..provider::ProviderOptions::default()
+    };
+
+    // Nothing is written if any identity fails
+    let mut revoked: Vec<(&String, Vec<String>)> = Vec::new();
+    for identity in identities {
+        match revoke_keys(
+            &mut authorized_keys,
+            identity,
+            &args.provider,
+            &provider_options,
+        ) {
+            Ok(removed) => revoked.push((identity, removed)),
+            Err(why) => return Err(format!("'{}': {}", identity, why).into()),
+        }
+    }
+    for line in revoked.iter().flat_map(|(_, removed)| removed) {
+        info!("Revoking key {}: {}", line_fingerprint(line), line);
+    }
+
+    let revoked_number: usize = revoked.iter().map(|(_, removed)| removed.len()).sum();
+    if revoked_number > 0 && !args.dry_run {
+        write_with_backup(path, &authorized_keys, args.backups)?;
+    }
+
+    Ok(if human_output {
+        let mut lines = Vec::new();
+        for (identity, removed) in &revoked {
+            lines.push(format!(
+                "{} {} SSH keys of '{}'.",
+                if args.dry_run {
+                    "Would revoke"
+                } else {
+                    "Revoked"
+                },
+                removed.len(),
+                identity
+            ));
+            lines.extend(removed.iter().filter_map(|line| {
+                let key = SshPublicKey::parse(line).ok()?;
+                Some(format!("  {}", describe(&key, args.md5)))
+            }));
+        }
+        if args.dry_run {
+            lines.push("Dry run, 'authorized_keys' file not changed.".to_string());
+        }
+        lines.join("\n")
+    } else {
+        let revoked: Vec<_> = revoked
+            .iter()
+            .map(|(identity, removed)| {
+                json!({
+                    "identity": identity,
+                    "keys": removed
+                        .iter()
+                        .map(|line| json!({ "key": line, "fingerprint": line_fingerprint(line) }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let mut output = json!({ "revoked": revoked });
+        if args.dry_run {
+            output["dry_run"] = json!(true);
+        }
+        output.to_string()
+    })
+}
+
+//
+// 'list' subcommand: keys of 'authorized_keys' file grouped by origin, in file order.
+// Return the output message (human or JSON)
+//
+fn list(path: &Path, md5: bool, human_output: bool) -> Result<String, ExitDisplay<String>> {
+    let authorized_keys = match AuthorizedKeys::read(path) {
+        Ok(authorized_keys) => authorized_keys,
+        Err(why) => {
+            return Err(format!("Error reading 'authorized_keys' file. Caused by {}", why).into())
+        }
+    };
+
+    let mut groups: Vec<(authorized_keys::Origin, Vec<String>)> = Vec::new();
+    for (origin, line) in authorized_keys.origins() {
+        match groups.iter_mut().find(|(present, _)| *present == origin) {
+            Some((_, lines)) => lines.push(line),
+            None => groups.push((origin, vec![line])),
+        }
+    }
+
+    Ok(if human_output {
+        if groups.is_empty() {
+            return Ok("No SSH keys in 'authorized_keys' file.".to_string());
+        }
+        let mut output = Vec::new();
+        for (origin, lines) in &groups {
+            output.push(format!("{} ({} keys)", origin, lines.len()));
+            output.extend(lines.iter().map(|line| match SshPublicKey::parse(line) {
+                Ok(key) => {
+                    let mut description = format!("  {} {}", key.bits(), describe(&key, md5));
+                    if let Some(options) = key.options() {
+                        description.push_str(&format!(" [{}]", options));
+                    }
+                    description
+                }
+                Err(why) => format!("  {}: {}", why, line),
+            }));
+        }
+        output.join("\n")
+    } else {
+        let groups: Vec<_> = groups
+            .iter()
+            .map(|(origin, lines)| {
+                let keys: Vec<_> = lines
+                    .iter()
+                    .map(|line| match SshPublicKey::parse(line) {
+                        Ok(key) => {
+                            let mut entry = json!({
+                                "key": line,
+                                "algorithm": key.algorithm(),
+                                "bits": key.bits(),
+                                "fingerprint": key.fingerprint(),
+                                "options": key.options(),
+                                "comment": key.comment(),
+                            });
+                            if md5 {
+                                entry["md5"] = json!(key.md5_fingerprint());
+                            }
+                            entry
+                        }
+                        Err(why) => json!({ "key": line, "error": why.to_string() }),
+                    })
+                    .collect();
+                json!({
+                    "origin": origin.to_string(),
+                    "managed": origin.is_managed(),
+                    "keys": keys,
+                })
+            })
+            .collect();
+        json!({ "groups": groups }).to_string()
+    })
+}
+
+//
+// 'check' subcommand: compare downloaded keys with 'authorized_keys' file (like a sync would do,
+// but nothing is written). Return the output message (human or JSON) and the exit code
+//
+fn check(
+    path: &Path,
+    identities: &[String],
+    args: &CliArgs,
+    human_output: bool,
+) -> Result<(String, i32), ExitDisplay<String>> {
+    let results = fetch_keys(identities, args)?;
+
+    // Keys can't be compared without knowing all of them (no keys upstream is fine)
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|result| match &result.keys {
+            Ok(..) | Err(error::Error::NoKeys) => None,
+            Err(why) => Some(format!("'{}': {}", result.identity, why)),
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    let mut authorized_keys = match AuthorizedKeys::read(path) {
+        Ok(authorized_keys) => authorized_keys,
+        Err(why) => {
+            return Err(format!("Error reading 'authorized_keys' file. Caused by {}", why).into())
+        }
+    };
+    let unmanaged: Vec<String> = authorized_keys
+        .origins()
+        .into_iter()
+        .filter(|(origin, _)| !origin.is_managed())
+        .map(|(_, line)| line)
+        .collect();
+
+    // Changes are made in memory only
+    let changes = match update_authorized_keys(&mut authorized_keys, &results, true) {
+        Ok(changes) => changes,
+        Err(why) => return Err(why.to_string().into()),
+    };
+    let missing = &changes.added;
+    // Keys written by older versions that a sync would move into their block are outdated too
+    let stale: Vec<String> = changes
+        .removed
+        .iter()
+        .chain(&changes.updated)
+        .chain(&changes.moved)
+        .cloned()
+        .collect();
+    // Tagged keys outside of blocks that may belong to anyone (i.e. 'from-GH-id-1'), a sync
+    // leaves them alone so they are only reported
+    let unattributed = &changes.unattributed;
+
+    let mut code = 0;
+    if !missing.is_empty() {
+        code += CHECK_MISSING;
+    }
+    if !stale.is_empty() {
+        code += CHECK_STALE;
+    }
+    if !unmanaged.is_empty() || !unattributed.is_empty() {
+        code += CHECK_UNMANAGED;
+    }
+    info!(
+        "{} missing keys, {} stale keys, {} unmanaged keys, {} unattributed keys",
+        missing.len(),
+        stale.len(),
+        unmanaged.len(),
+        unattributed.len()
+    );
+
+    let output = if human_output {
+        let mut lines = Vec::new();
+        if code == 0 {
+            lines.push(format!(
+                "'authorized_keys' file is in sync with {} identities.",
+                identities.len()
+            ));
+        }
+        for (title, keys) in &[
+            ("Missing", missing),
+            ("Stale or outdated", &stale),
+            ("Unmanaged", &unmanaged),
+            ("Unattributed tagged", unattributed),
+        ] {
+            if !keys.is_empty() {
+                lines.push(format!("{} SSH keys ({}):", title, keys.len()));
+                lines.extend(keys.iter().filter_map(|line| {
+                    let key = SshPublicKey::parse(line).ok()?;
+                    Some(format!("  {}", describe(&key, args.md5)))
+                }));
+            }
+        }
+        lines.join("\n")
+    } else {
+        let entries = |keys: &[String]| -> Vec<_> {
+            keys.iter()
+                .map(|line| json!({ "key": line, "fingerprint": line_fingerprint(line) }))
+                .collect()
+        };
+        json!({
+            "in_sync": code == 0,
+            "missing": entries(missing),
+            "stale": entries(&stale),
+            "unmanaged": entries(&unmanaged),
+            "unattributed": entries(unattributed),
+        })
+        .to_string()
+    };
+
+    Ok((output, code))
+}
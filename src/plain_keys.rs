@@ -0,0 +1,211 @@
+extern crate pretty_env_logger;
+
+use crate::error::{self, Error, Result};
+use crate::provider::{self, KeyProvider, TagScope};
+use crate::ssh_key::SshPublicKey;
+
+///
+/// Download a plain text list of keys (`authorized_keys` style, one key per line)
+///
+/// Keys are parsed with `provider::parse_plain_keys` and returned without origin tag. `identity`
+/// is only used in errors (i.e. `Error::NotFound`).
+///
+/// # Errors
+///
+/// Return the errors of `error::check_response` for non 2XX responses.
+///
+pub fn fetch_keys(url: &str, identity: &str) -> Result<Vec<SshPublicKey>> {
+    let response = error::check_response(ureq::get(url).call(), identity)?;
+
+    let body = error::read_body(response)?;
+
+    Ok(provider::parse_plain_keys(&body))
+}
+
+///
+/// Key provider for services publishing user keys as plain text (i.e. Launchpad and SourceHut)
+///
+/// The keys URL is built replacing `{username}` in `url` (a leading `~` of the identity is
+/// ignored, like Launchpad and SourceHut do).
+///
+/// Output keys format is the following:
+/// `<SSH_KEY> from-<SOURCE>-id-<USERNAME>`
+///
+/// > Plain text keys have no public id, so the username is used instead.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::plain_keys::PlainKeysClient;
+/// use superkeyloader_lib::provider::KeyProvider;
+///
+/// let client = PlainKeysClient::new(
+///     ("example", "Example"),
+///     "EX",
+///     "https://keys.example/~{username}.keys",
+///     |username| !username.is_empty(),
+/// );
+///
+/// assert_eq!(client.name(), "example");
+/// assert_eq!(client.url("~alice"), "https://keys.example/~alice.keys");
+/// assert!(client.validate_identity("~alice"));
+/// ```
+///
+pub struct PlainKeysClient {
+    name: &'static str,
+    display_name: &'static str,
+    source: &'static str,
+    url: String,
+    validate_username: fn(&str) -> bool,
+}
+
+impl PlainKeysClient {
+    ///
+    /// `names` are the provider name and display name, `source` the origin tag source
+    ///
+    pub fn new(
+        names: (&'static str, &'static str),
+        source: &'static str,
+        url: &str,
+        validate_username: fn(&str) -> bool,
+    ) -> PlainKeysClient {
+        PlainKeysClient {
+            name: names.0,
+            display_name: names.1,
+            source,
+            url: url.to_string(),
+            validate_username,
+        }
+    }
+
+    /// Keys URL of an identity
+    pub fn url(&self, identity: &str) -> String {
+        self.url
+            .replace("{username}", identity.trim_start_matches('~'))
+    }
+}
+
+impl KeyProvider for PlainKeysClient {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn display_name(&self) -> &str {
+        self.display_name
+    }
+
+    fn validate_identity(&self, identity: &str) -> bool {
+        (self.validate_username)(identity)
+    }
+
+    fn tag_scope(&self, identity: &str) -> TagScope {
+        TagScope::new(self.source, Some(identity.trim_start_matches('~')))
+    }
+
+    ///
+    /// Download user's SSH keys
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidUsername` if the username isn't valid,
+    /// and the errors of `error::check_response` for non 2XX responses.
+    ///
+    fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>> {
+        if !(self.validate_username)(identity) {
+            return Err(Error::InvalidUsername(identity.to_string()));
+        }
+        let username = identity.trim_start_matches('~');
+
+        let url = self.url(identity);
+        debug!("{} endpoint URL: {}", self.display_name, url);
+
+        fetch_keys(&url, username)?
+            .into_iter()
+            .map(|key| provider::tag_key(&key, self.source, username))
+            .collect()
+    }
+}
+
+pub mod test_values {
+
+    pub const VALID_USERNAME: &str = "~testuser";
+    pub const MISSING_USERNAME: &str = "erruser";
+    pub const INVALID_USERNAME: &str = "Test_User";
+
+    pub const VALID_2_KEYS_TEXT: &str = "\
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg testuser@laptop
+
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAefJue1kiGa+dbcbbd2WBO7EPPwFF0vFD8mFtIN3FHz
+";
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_values::*;
+    use super::PlainKeysClient;
+    use crate::error::Error;
+    use crate::provider::KeyProvider;
+
+    use mockito::mock;
+
+    fn init() {
+        let _ = pretty_env_logger::env_logger::builder()
+            .is_test(true)
+            .try_init();
+    }
+
+    fn client() -> PlainKeysClient {
+        PlainKeysClient::new(
+            ("test", "Test"),
+            "TS",
+            &format!("{}/~{{username}}.keys", mockito::server_url()),
+            |username| username.chars().all(|c| c == '~' || c.is_ascii_lowercase()),
+        )
+    }
+
+    #[test]
+    fn valid_response() {
+        init();
+        let _m = mock("GET", "/~testuser.keys")
+            .with_status(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body(VALID_2_KEYS_TEXT)
+            .create();
+
+        let keys = client().get_keys(VALID_USERNAME).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0].to_string(),
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg from-TS-id-testuser"
+        );
+        assert!(client()
+            .tag_scope(VALID_USERNAME)
+            .matches(&keys[1].to_string()));
+    }
+
+    #[test]
+    fn missing_username() {
+        init();
+        let _m = mock("GET", "/~erruser.keys")
+            .with_status(404)
+            .with_body("Not found")
+            .create();
+
+        assert!(matches!(
+            client().get_keys(MISSING_USERNAME),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_username() {
+        init();
+
+        assert!(matches!(
+            client().get_keys(INVALID_USERNAME),
+            Err(Error::InvalidUsername(_))
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::{Error, Result};
+use crate::ssh_key::SshPublicKey;
+
+/// Algorithms allowed by default, every known one except the deprecated `ssh-dss`
+pub const DEFAULT_ALGORITHMS: &[&str] = &[
+    "ssh-rsa",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Minimum RSA key size allowed by default (NIST recommends 3072 bits beyond 2030)
+pub const DEFAULT_MIN_RSA_BITS: usize = 3072;
+
+///
+/// Rules a key must follow to be written into `authorized_keys`
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::policy::KeyPolicy;
+/// use superkeyloader_lib::ssh_key::SshPublicKey;
+///
+/// let key = SshPublicKey::parse(
+///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg",
+/// )
+/// .unwrap();
+///
+/// assert!(KeyPolicy::default().check(&key).is_ok());
+///
+/// let policy = KeyPolicy {
+///     require_security_key: true,
+///     ..KeyPolicy::default()
+/// };
+///
+/// assert!(policy.check(&key).is_err());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPolicy {
+    /// Key algorithms allowed (i.e. `ssh-ed25519`)
+    pub allowed_algorithms: Vec<String>,
+    /// Minimum RSA modulus size in bits
+    pub min_rsa_bits: usize,
+    /// Allow only keys backed by a FIDO security key (`sk-*` algorithms)
+    pub require_security_key: bool,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> KeyPolicy {
+        KeyPolicy {
+            allowed_algorithms: DEFAULT_ALGORITHMS.iter().map(|a| a.to_string()).collect(),
+            min_rsa_bits: DEFAULT_MIN_RSA_BITS,
+            require_security_key: false,
+        }
+    }
+}
+
+impl KeyPolicy {
+    ///
+    /// Check a key against the policy
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::PolicyViolation` with the reason why the key isn't allowed.
+    ///
+    pub fn check(&self, key: &SshPublicKey) -> Result<()> {
+        let algorithm = key.algorithm();
+
+        if !self
+            .allowed_algorithms
+            .iter()
+            .any(|allowed| allowed == algorithm)
+        {
+            return Err(Error::PolicyViolation(format!(
+                "algorithm '{}' is not allowed",
+                algorithm
+            )));
+        }
+        if self.require_security_key && !key.is_security_key() {
+            return Err(Error::PolicyViolation(
+                "key is not backed by a security key".to_string(),
+            ));
+        }
+        if algorithm == "ssh-rsa" && key.bits() < self.min_rsa_bits {
+            return Err(Error::PolicyViolation(format!(
+                "RSA key has {} bits, at least {} are required",
+                key.bits(),
+                self.min_rsa_bits
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// A key that was downloaded but not allowed by the policy
+///
+#[derive(Debug)]
+pub struct RejectedKey {
+    pub key: SshPublicKey,
+    /// `Error::PolicyViolation` describing why the key was rejected
+    pub reason: Error,
+}
+
+///
+/// Split keys in allowed and rejected ones, rejected keys are logged with their fingerprint
+///
+pub fn apply(policy: &KeyPolicy, keys: Vec<SshPublicKey>) -> (Vec<SshPublicKey>, Vec<RejectedKey>) {
+    let mut allowed = Vec::new();
+    let mut rejected = Vec::new();
+
+    for key in keys {
+        match policy.check(&key) {
+            Ok(()) => allowed.push(key),
+            Err(reason) => {
+                warn!("Rejecting key {}: {}", key.fingerprint(), reason);
+                rejected.push(RejectedKey { key, reason });
+            }
+        }
+    }
+
+    (allowed, rejected)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{apply, KeyPolicy};
+    use crate::error::Error;
+    use crate::ssh_key::test_values::*;
+    use crate::ssh_key::SshPublicKey;
+
+    fn keys(lines: &[&str]) -> Vec<SshPublicKey> {
+        lines
+            .iter()
+            .map(|line| SshPublicKey::parse(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn default_policy() {
+        let (allowed, rejected) = apply(
+            &KeyPolicy::default(),
+            keys(&[
+                ED25519_KEY,
+                RSA_2048_KEY,
+                RSA_1024_KEY,
+                DSA_KEY,
+                SK_ED25519_KEY,
+            ]),
+        );
+
+        assert_eq!(allowed, keys(&[ED25519_KEY, SK_ED25519_KEY]));
+        assert_eq!(rejected.len(), 3);
+        assert!(rejected[0].reason.to_string().contains("2048 bits"));
+        assert!(rejected[0].reason.to_string().contains("at least 3072"));
+        assert!(rejected[1].reason.to_string().contains("1024 bits"));
+        assert!(matches!(rejected[2].reason, Error::PolicyViolation(_)));
+        assert!(rejected[2].reason.to_string().contains("'ssh-dss'"));
+    }
+
+    #[test]
+    fn strict_policy() {
+        let policy = KeyPolicy {
+            min_rsa_bits: 4096,
+            ..KeyPolicy::default()
+        };
+        let (allowed, _) = apply(&policy, keys(&[ED25519_KEY, RSA_2048_KEY]));
+        assert_eq!(allowed, keys(&[ED25519_KEY]));
+
+        let policy = KeyPolicy {
+            allowed_algorithms: vec!["ssh-ed25519".into(), "sk-ssh-ed25519@openssh.com".into()],
+            require_security_key: true,
+            ..KeyPolicy::default()
+        };
+        let (allowed, rejected) =
+            apply(&policy, keys(&[ED25519_KEY, SK_ED25519_KEY, ECDSA_256_KEY]));
+        assert_eq!(allowed, keys(&[SK_ED25519_KEY]));
+        assert!(rejected[0].reason.to_string().contains("security key"));
+        assert!(rejected[1].reason.to_string().contains("not allowed"));
+    }
+}
@@ -0,0 +1,519 @@
+use std::fmt::Display;
+
+use crate::bitbucket::{self, BitbucketClient};
+use crate::error::Result;
+use crate::gitea::{self, GiteaClient};
+use crate::github::{self, GithubClient};
+use crate::gitlab::{self, GitlabClient};
+use crate::launchpad;
+use crate::sourcehut;
+use crate::ssh_key::SshPublicKey;
+use crate::url::{self, UrlProvider};
+
+/// Names of all the available providers (used by the CLI `--provider` flag)
+pub const PROVIDERS: &[&str] = &[
+    "github",
+    "gitlab",
+    "bitbucket",
+    "gitea",
+    "launchpad",
+    "sourcehut",
+    "url",
+];
+
+/// SSH public key algorithms accepted (see `ssh_key::SshPublicKey`)
+pub const KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Short provider aliases, usable as identity prefix (i.e. `gh:alice`)
+pub const ALIASES: &[(&str, &str)] = &[
+    ("gh", "github"),
+    ("gl", "gitlab"),
+    ("bb", "bitbucket"),
+    ("gt", "gitea"),
+    ("lp", "launchpad"),
+    ("sh", "sourcehut"),
+];
+
+///
+/// Settings used to build providers
+///
+/// Every provider only reads what it needs (i.e. `gitlab_url` is used only by GitLab), credentials
+/// included: each one is sent only to the instance of its own provider.
+///
+#[derive(Debug, Clone)]
+pub struct ProviderOptions {
+    /// GitHub API token, sent only to `github_url`
+    pub github_token: Option<String>,
+    /// GitLab personal access token, sent only to `gitlab_url`
+    pub gitlab_token: Option<String>,
+    /// Bitbucket app password in the `<USERNAME>:<APP_PASSWORD>` format
+    pub bitbucket_app_password: Option<String>,
+    /// Gitea/Forgejo access token, sent only to `gitea_url`
+    pub gitea_token: Option<String>,
+    /// GitHub API base URL (defaults to `https://api.github.com`)
+    pub github_url: String,
+    /// GitLab instance base URL (defaults to `https://gitlab.com`)
+    pub gitlab_url: String,
+    /// Gitea/Forgejo instance base URL (defaults to `https://codeberg.org`)
+    pub gitea_url: String,
+    /// Accept plain HTTP key URLs (`url` provider)
+    pub allow_http: bool,
+}
+
+impl Default for ProviderOptions {
+    fn default() -> ProviderOptions {
+        ProviderOptions {
+            github_token: None,
+            gitlab_token: None,
+            bitbucket_app_password: None,
+            gitea_token: None,
+            github_url: github::DEFAULT_BASE_URL.to_string(),
+            gitlab_url: gitlab::DEFAULT_BASE_URL.to_string(),
+            gitea_url: gitea::DEFAULT_BASE_URL.to_string(),
+            allow_http: false,
+        }
+    }
+}
+
+///
+/// A source of SSH public keys (GitHub, GitLab, a plain URL, etc.)
+///
+/// Every backend implements this trait, so the CLI (and library consumers) can treat them all the
+/// same way and pick one by name with `from_name`.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::error::Result;
+/// use superkeyloader_lib::provider::{self, KeyProvider, TagScope};
+/// use superkeyloader_lib::ssh_key::SshPublicKey;
+///
+/// struct StaticProvider;
+///
+/// impl KeyProvider for StaticProvider {
+///     fn name(&self) -> &str {
+///         "static"
+///     }
+///
+///     fn display_name(&self) -> &str {
+///         "Static"
+///     }
+///
+///     fn validate_identity(&self, identity: &str) -> bool {
+///         !identity.is_empty()
+///     }
+///
+///     fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>> {
+///         let key = SshPublicKey::parse(
+///             "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg",
+///         )?;
+///         Ok(vec![provider::tag_key(&key, "ST", identity)?])
+///     }
+///
+///     fn tag_scope(&self, _identity: &str) -> TagScope {
+///         TagScope::new("ST", None)
+///     }
+/// }
+///
+/// let provider = StaticProvider;
+///
+/// assert!(provider.validate_identity("alice"));
+/// assert_eq!(provider.get_keys("alice").unwrap().len(), 1);
+/// ```
+///
+pub trait KeyProvider {
+    /// Short machine-friendly name (i.e. `github`)
+    fn name(&self) -> &str;
+
+    /// Human-friendly name used in output messages (i.e. `GitHub`)
+    fn display_name(&self) -> &str;
+
+    /// Check if `identity` (usually a username) is acceptable for this provider
+    fn validate_identity(&self, identity: &str) -> bool;
+
+    ///
+    /// Download all the SSH keys of `identity`
+    ///
+    /// # Errors
+    ///
+    /// Return an `Error` describing why keys couldn't be downloaded (invalid username, missing
+    /// user, rate limits, etc.).
+    ///
+    fn get_keys(&self, identity: &str) -> Result<Vec<SshPublicKey>>;
+
+    ///
+    /// Origin tags of the keys written for `identity` (see `tag_key`)
+    ///
+    /// Used by sync mode to find which managed keys belong to this provider.
+    ///
+    fn tag_scope(&self, identity: &str) -> TagScope;
+}
+
+///
+/// Append the origin tag to a key comment
+///
+/// Every provider tags keys the same way, so they're traceable to where they came from:
+/// `<SSH_KEY> [<COMMENT>] from-<SOURCE>-id-<KEY_ID>`
+///
+/// Time-limited keys get a `grant-until-<TIME>` tag right before it (see `grant::grant_key`),
+/// so the origin tag is always the last word.
+///
+/// # Errors
+///
+/// Return `Error::InvalidKey` if the tag has control characters.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::tag_key;
+/// use superkeyloader_lib::ssh_key::SshPublicKey;
+///
+/// let key = SshPublicKey::parse(
+///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg",
+/// )
+/// .unwrap();
+/// let key = tag_key(&key, "GH", 22932337).unwrap();
+///
+/// assert_eq!(key.comment(), Some("from-GH-id-22932337"));
+/// ```
+///
+pub fn tag_key<T: Display>(key: &SshPublicKey, source: &str, id: T) -> Result<SshPublicKey> {
+    let tag = match key.comment() {
+        Some(comment) => format!("{} from-{}-id-{}", comment, source, id),
+        None => format!("from-{}-id-{}", source, id),
+    };
+    key.clone().with_comment(Some(&tag))
+}
+
+///
+/// Parse a key sent by a provider API and append its origin tag (see `tag_key`)
+///
+/// Invalid keys are rejected (and logged), that includes keys with `authorized_keys` options:
+/// a provider must never decide what a key is allowed to do.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::parse_key;
+///
+/// let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg";
+///
+/// assert!(parse_key(key, "GH", 1).is_some());
+/// assert!(parse_key(&format!("no-pty {}", key), "GH", 1).is_none());
+/// assert!(parse_key(&format!("{}\nssh-rsa AAAA", key), "GH", 1).is_none());
+/// ```
+///
+pub fn parse_key<T: Display>(text: &str, source: &str, id: T) -> Option<SshPublicKey> {
+    let key = match SshPublicKey::parse(text) {
+        Ok(key) if key.options().is_some() => {
+            warn!("Rejecting key with 'authorized_keys' options: '{}'", text);
+            return None;
+        }
+        Ok(key) => key,
+        Err(why) => {
+            warn!("Rejecting key {:?}: {}", text, why);
+            return None;
+        }
+    };
+    match tag_key(&key, source, id) {
+        Ok(key) => Some(key),
+        Err(why) => {
+            warn!("Rejecting key {:?}: {}", text, why);
+            None
+        }
+    }
+}
+
+///
+/// Set of origin tags (`from-<SOURCE>-id-<KEY_ID>`) written by a provider for an identity
+///
+/// When key ids are per key (i.e. GitHub) `id` is `None` and every key of the source matches,
+/// when the id is the username (i.e. Launchpad) only that user keys match.
+///
+/// A scope is `attributable` when its tags can only belong to that identity: the id is the
+/// username, or the source is used by a single identity (i.e. a keys URL). GitHub style tags
+/// are shared by every user of the provider, so keys outside of managed blocks with those tags
+/// can't be tied to anyone and are never removed (see `AuthorizedKeys::remove_stale`).
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::TagScope;
+///
+/// let github = TagScope::new("GH", None);
+/// let launchpad = TagScope::new("LP", Some("alice"));
+/// let url = TagScope::single_identity("https://keys.example/carol");
+///
+/// assert!(github.matches("ssh-rsa AAAAB3N from-GH-id-22932337"));
+/// assert!(!github.matches("ssh-rsa AAAAB3N from-GL-id-22932337"));
+/// assert!(launchpad.matches("ssh-rsa AAAAB3N laptop from-LP-id-alice"));
+/// assert!(!launchpad.matches("ssh-rsa AAAAB3N from-LP-id-bob"));
+/// assert!(!launchpad.matches("ssh-rsa AAAAB3N"));
+///
+/// assert!(!github.attributable);
+/// assert!(launchpad.attributable);
+/// assert!(url.attributable);
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagScope {
+    pub source: String,
+    pub id: Option<String>,
+    /// Tags can only belong to the identity of this scope
+    pub attributable: bool,
+}
+
+impl TagScope {
+    pub fn new(source: &str, id: Option<&str>) -> TagScope {
+        TagScope {
+            source: source.to_string(),
+            id: id.map(String::from),
+            attributable: id.is_some(),
+        }
+    }
+
+    ///
+    /// Scope of a source used only by one identity (i.e. a keys URL), every key id matches
+    ///
+    pub fn single_identity(source: &str) -> TagScope {
+        TagScope {
+            source: source.to_string(),
+            id: None,
+            attributable: true,
+        }
+    }
+
+    ///
+    /// Check if the origin tag of a key line (its last word) is in this scope
+    ///
+    pub fn matches(&self, line: &str) -> bool {
+        let tag = match line.split_whitespace().last() {
+            Some(tag) => tag,
+            None => return false,
+        };
+        let prefix = format!("from-{}-id-", self.source);
+        if !tag.starts_with(&prefix) || tag.len() == prefix.len() {
+            return false;
+        }
+        match &self.id {
+            Some(id) => &tag[prefix.len()..] == id,
+            None => true,
+        }
+    }
+}
+
+///
+/// Source and id of the origin tag of a key line (its last word), `None` if it isn't tagged
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::parse_tag;
+///
+/// assert_eq!(
+///     parse_tag("ssh-rsa AAAAB3N laptop from-GH-id-22932337"),
+///     Some(("GH", "22932337"))
+/// );
+/// assert_eq!(parse_tag("ssh-rsa AAAAB3N from-LP-id-"), None);
+/// assert_eq!(parse_tag("ssh-rsa AAAAB3N alice@laptop"), None);
+/// ```
+///
+pub fn parse_tag(line: &str) -> Option<(&str, &str)> {
+    let tag = line.split_whitespace().last()?.strip_prefix("from-")?;
+    let separator = tag.find("-id-")?;
+    let (source, id) = (&tag[..separator], &tag[separator + "-id-".len()..]);
+
+    if source.is_empty() || id.is_empty() {
+        return None;
+    }
+    Some((source, id))
+}
+
+///
+/// Parse a plain text response with one key per line (`authorized_keys` style)
+///
+/// Blank lines and `#` comments are skipped. The key comment is dropped (the origin tag is
+/// appended later).
+///
+/// Lines that aren't valid public keys are rejected (and logged), that includes lines with
+/// `authorized_keys` options: they can't be dropped without changing the meaning of the key.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::parse_plain_keys;
+///
+/// let body = "# Alice keys\n\nssh-ed25519 \
+///     AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg alice@laptop\n";
+/// let keys = parse_plain_keys(body);
+///
+/// assert_eq!(keys.len(), 1);
+/// assert_eq!(keys[0].comment(), None);
+///
+/// let garbage = "<html>Not found</html>\nno-pty ssh-ed25519 \
+///     AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg\n";
+///
+/// assert!(parse_plain_keys(garbage).is_empty());
+/// ```
+///
+pub fn parse_plain_keys(body: &str) -> Vec<SshPublicKey> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match SshPublicKey::parse(line) {
+            Ok(key) if key.options().is_none() => key.with_comment(None).ok(),
+            Ok(..) => {
+                warn!(
+                    "Rejecting key line with 'authorized_keys' options: '{}'",
+                    line
+                );
+                None
+            }
+            Err(why) => {
+                warn!("Rejecting invalid key line '{}': {}", line, why);
+                None
+            }
+        })
+        .collect()
+}
+
+///
+/// Split an identity in its (optional) provider prefix and the identity itself
+///
+/// HTTP(S) URLs are identities of the `url` provider.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::split_identity;
+///
+/// assert_eq!(split_identity("lp:alice"), (Some("lp"), "alice"));
+/// assert_eq!(split_identity("alice"), (None, "alice"));
+/// assert_eq!(
+///     split_identity("https://keys.example/carol"),
+///     (Some("url"), "https://keys.example/carol")
+/// );
+/// ```
+///
+pub fn split_identity(identity: &str) -> (Option<&str>, &str) {
+    if identity.starts_with("http://") || identity.starts_with("https://") {
+        return (Some("url"), identity);
+    }
+
+    let mut parts = identity.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(identity)) => (Some(prefix), identity),
+        _ => (None, identity),
+    }
+}
+
+///
+/// Resolve a provider alias (i.e. `gh`) to the provider name (i.e. `github`)
+///
+/// Full provider names are returned as they are, unknown names return `None`.
+///
+pub fn resolve_name(name: &str) -> Option<&'static str> {
+    PROVIDERS
+        .iter()
+        .find(|provider| **provider == name)
+        .copied()
+        .or_else(|| {
+            ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map(|(_, provider)| *provider)
+        })
+}
+
+///
+/// Canonical form of an identity, used as managed block name in `authorized_keys`
+///
+/// The provider is always explicit and written with its alias (if any). Keys URLs, with or without
+/// the `url:` prefix, are written as their `url::url_id`: credentials and query string are never
+/// written.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::canonical_identity;
+///
+/// assert_eq!(canonical_identity("alice", "github"), "gh:alice");
+/// assert_eq!(canonical_identity("gitlab:alice", "github"), "gl:alice");
+/// assert_eq!(
+///     canonical_identity("https://keys.example/carol", "github"),
+///     "https://keys.example/carol"
+/// );
+/// assert_eq!(
+///     canonical_identity("url:https://keys.example/carol", "github"),
+///     "https://keys.example/carol"
+/// );
+/// assert!(!canonical_identity("https://keys.example/carol?token=secret", "github")
+///     .contains("secret"));
+/// ```
+///
+pub fn canonical_identity(identity: &str, default_provider: &str) -> String {
+    let (prefix, username) = split_identity(identity);
+    let name = match resolve_name(prefix.unwrap_or(default_provider)) {
+        Some("url") => return url::url_id(username),
+        Some(name) => name,
+        None => return identity.to_string(),
+    };
+    let short_name = ALIASES
+        .iter()
+        .find(|(_, provider)| *provider == name)
+        .map_or(name, |(alias, _)| alias);
+
+    format!("{}:{}", short_name, username)
+}
+
+///
+/// Build a provider from its name (or alias)
+///
+/// Return `None` if there is no provider called `name`.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::{from_name, ProviderOptions};
+///
+/// let options = ProviderOptions::default();
+/// let provider = from_name("github", &options).unwrap();
+///
+/// assert_eq!(provider.display_name(), "GitHub");
+/// assert_eq!(from_name("lp", &options).unwrap().name(), "launchpad");
+/// assert!(from_name("missing", &options).is_none());
+/// ```
+///
+pub fn from_name(name: &str, options: &ProviderOptions) -> Option<Box<dyn KeyProvider>> {
+    match resolve_name(name)? {
+        "github" => Some(Box::new(GithubClient::new(
+            &options.github_url,
+            options.github_token.clone(),
+        ))),
+        "gitlab" => Some(Box::new(GitlabClient::new(
+            &options.gitlab_url,
+            options.gitlab_token.clone(),
+        ))),
+        "bitbucket" => Some(Box::new(BitbucketClient::new(
+            bitbucket::DEFAULT_BASE_URL,
+            options.bitbucket_app_password.clone(),
+        ))),
+        "gitea" => Some(Box::new(GiteaClient::new(
+            &options.gitea_url,
+            options.gitea_token.clone(),
+        ))),
+        "launchpad" => Some(Box::new(launchpad::client(launchpad::DEFAULT_BASE_URL))),
+        "sourcehut" => Some(Box::new(sourcehut::client(sourcehut::DEFAULT_BASE_URL))),
+        "url" => Some(Box::new(UrlProvider::new(options.allow_http))),
+        _ => None,
+    }
+}
@@ -0,0 +1,61 @@
+use regex::Regex;
+
+use crate::plain_keys::PlainKeysClient;
+
+/// SourceHut accounts service base URL
+pub const DEFAULT_BASE_URL: &str = "https://meta.sr.ht";
+
+///
+/// Validate SourceHut usernames (a leading `~` is allowed and ignored)
+///
+/// # Rules
+///   - Max 30 characters, lowercase alphanumerical, '_' and '-'
+///   - Must start with a letter or '_' and be at least 2 characters long
+///
+fn validate_username(username: &str) -> bool {
+    let username_rule = Regex::new(r"^[a-z_][-a-z\d_]{1,29}$").unwrap();
+    username_rule.is_match(username.trim_start_matches('~'))
+}
+
+///
+/// SourceHut key provider
+///
+/// URL: `GET https://meta.sr.ht/~<USERNAME>.keys`
+///
+/// The response is plain text, one key per line. Keys are tagged `from-SH-id-<USERNAME>` (see
+/// `PlainKeysClient`).
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::provider::KeyProvider;
+/// use superkeyloader_lib::sourcehut::{self, DEFAULT_BASE_URL};
+///
+/// let sourcehut = sourcehut::client(DEFAULT_BASE_URL);
+///
+/// assert_eq!(sourcehut.name(), "sourcehut");
+/// assert_eq!(sourcehut.url("bob"), "https://meta.sr.ht/~bob.keys");
+/// assert!(sourcehut.validate_identity("~bob"));
+/// assert!(!sourcehut.validate_identity("1bob"));
+/// ```
+///
+pub fn client(base_url: &str) -> PlainKeysClient {
+    PlainKeysClient::new(
+        ("sourcehut", "SourceHut"),
+        "SH",
+        &format!("{}/~{{username}}.keys", base_url.trim_end_matches('/')),
+        validate_username,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_sourcehut_username_validation() {
+        assert!(super::validate_username("~testuser"));
+        assert!(super::validate_username("_test-user"));
+        assert!(!super::validate_username("test.user"));
+        assert!(!super::validate_username("~"));
+    }
+}
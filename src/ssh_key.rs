@@ -0,0 +1,473 @@
+use std::fmt;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::authorized_keys::KeyLine;
+use crate::error::{Error, Result};
+use crate::key_options::KeyOptions;
+
+///
+/// A field of a public key blob (SSH wire format, RFC 4253 section 6.6)
+///
+enum Field {
+    /// Multiple precision integer, must be positive
+    Mpint,
+    /// String of an exact length
+    Bytes(usize),
+    /// String with an exact value
+    Text(&'static str),
+    /// Uncompressed elliptic curve point of an exact length
+    Point(usize),
+    /// Any non-empty string
+    Any,
+}
+
+///
+/// Fields following the algorithm name in the key blob, `None` for unknown algorithms
+///
+fn blob_fields(algorithm: &str) -> Option<&'static [Field]> {
+    let fields: &'static [Field] = match algorithm {
+        "ssh-rsa" => &[Field::Mpint, Field::Mpint],
+        "ssh-dss" => &[Field::Mpint, Field::Mpint, Field::Mpint, Field::Mpint],
+        "ssh-ed25519" => &[Field::Bytes(32)],
+        "ecdsa-sha2-nistp256" => &[Field::Text("nistp256"), Field::Point(65)],
+        "ecdsa-sha2-nistp384" => &[Field::Text("nistp384"), Field::Point(97)],
+        "ecdsa-sha2-nistp521" => &[Field::Text("nistp521"), Field::Point(133)],
+        "sk-ssh-ed25519@openssh.com" => &[Field::Bytes(32), Field::Any],
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            &[Field::Text("nistp256"), Field::Point(65), Field::Any]
+        }
+        _ => return None,
+    };
+    Some(fields)
+}
+
+///
+/// Read length-prefixed strings out of a key blob
+///
+struct WireReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    fn read_string(&mut self) -> Option<&'a [u8]> {
+        if self.data.len() < 4 {
+            return None;
+        }
+        let (length, rest) = self.data.split_at(4);
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        if rest.len() < length {
+            return None;
+        }
+        let (value, rest) = rest.split_at(length);
+        self.data = rest;
+        Some(value)
+    }
+}
+
+///
+/// Check that a decoded key blob is a well-formed `algorithm` public key
+///
+fn check_blob(algorithm: &str, blob: &[u8]) -> std::result::Result<(), String> {
+    let fields =
+        blob_fields(algorithm).ok_or_else(|| format!("unknown key type '{}'", algorithm))?;
+    let mut reader = WireReader { data: blob };
+
+    let name = reader.read_string().ok_or("truncated key data")?;
+    if name != algorithm.as_bytes() {
+        return Err(format!(
+            "key data is a '{}' key, not a '{}' key",
+            String::from_utf8_lossy(name),
+            algorithm
+        ));
+    }
+
+    for field in fields {
+        let value = reader.read_string().ok_or("truncated key data")?;
+        let valid = match field {
+            Field::Mpint => !value.is_empty() && value[0] & 0x80 == 0,
+            Field::Bytes(length) => value.len() == *length,
+            Field::Text(text) => value == text.as_bytes(),
+            Field::Point(length) => value.len() == *length && value[0] == 0x04,
+            Field::Any => !value.is_empty(),
+        };
+        if !valid {
+            return Err(format!("malformed '{}' key data", algorithm));
+        }
+    }
+
+    if !reader.data.is_empty() {
+        return Err("unexpected bytes after key data".to_string());
+    }
+
+    Ok(())
+}
+
+///
+/// Size in bits of a (positive) multiple precision integer
+///
+fn mpint_bits(value: &[u8]) -> usize {
+    let first = value
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(value.len());
+    match value.get(first) {
+        Some(byte) => (value.len() - first - 1) * 8 + (8 - byte.leading_zeros() as usize),
+        None => 0,
+    }
+}
+
+///
+/// Check that a text that ends up in `authorized_keys` can't break out of its line
+///
+fn check_text(text: &str) -> Result<()> {
+    match text.chars().find(|c| c.is_control() && *c != '\t') {
+        Some(c) => Err(Error::InvalidKey(format!(
+            "control character {:?} is not allowed",
+            c
+        ))),
+        None => Ok(()),
+    }
+}
+
+///
+/// A validated SSH public key, as written in `authorized_keys` files
+///
+/// Format: `[<OPTIONS>] <ALGORITHM> <BASE64_DATA> [<COMMENT>]`
+///
+/// Key data is decoded and checked against the declared algorithm, so only well-formed keys can
+/// be built. Control characters (newlines included) are rejected everywhere, a key is always a
+/// single line.
+///
+/// # Example
+///
+/// ```
+/// use superkeyloader_lib::ssh_key::SshPublicKey;
+///
+/// let line = "no-pty ssh-ed25519 \
+///     AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg alice@laptop";
+/// let key = SshPublicKey::parse(line).unwrap();
+///
+/// assert_eq!(key.options(), Some("no-pty"));
+/// assert_eq!(key.algorithm(), "ssh-ed25519");
+/// assert_eq!(key.comment(), Some("alice@laptop"));
+/// assert_eq!(key.blob().len(), 51);
+/// assert_eq!(key.to_string(), line);
+///
+/// // Truncated key data
+/// assert!(SshPublicKey::parse("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5").is_err());
+/// // Key data of another algorithm
+/// assert!(SshPublicKey::parse(
+///     "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg"
+/// )
+/// .is_err());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshPublicKey {
+    options: Option<String>,
+    algorithm: String,
+    data: String,
+    comment: Option<String>,
+    blob: Vec<u8>,
+}
+
+impl SshPublicKey {
+    ///
+    /// Parse and validate a public key line
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidKey` if the line has control characters, the algorithm is unknown,
+    /// or key data isn't valid base64 or doesn't match the algorithm.
+    ///
+    pub fn parse(line: &str) -> Result<SshPublicKey> {
+        check_text(line)?;
+
+        let line = KeyLine::parse(line)
+            .ok_or_else(|| Error::InvalidKey("unknown key type or missing key data".to_string()))?;
+        let blob = base64::decode(&line.key_data)
+            .map_err(|why| Error::InvalidKey(format!("key data isn't valid base64 ({})", why)))?;
+        check_blob(&line.key_type, &blob).map_err(Error::InvalidKey)?;
+
+        Ok(SshPublicKey {
+            options: line.options,
+            algorithm: line.key_type,
+            data: line.key_data,
+            comment: line.comment,
+            blob,
+        })
+    }
+
+    /// `authorized_keys` options (i.e. `no-pty,from="10.0.0.1"`)
+    pub fn options(&self) -> Option<&str> {
+        self.options.as_deref()
+    }
+
+    /// Key algorithm (i.e. `ssh-ed25519`)
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Base64 encoded key data
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Decoded key data (SSH wire format)
+    pub fn blob(&self) -> &[u8] {
+        &self.blob
+    }
+
+    ///
+    /// Key size in bits (RSA modulus, DSA prime, elliptic curve size)
+    ///
+    pub fn bits(&self) -> usize {
+        let mut reader = WireReader { data: &self.blob };
+        reader.read_string();
+
+        match self.algorithm.as_str() {
+            "ssh-rsa" => {
+                // Public exponent comes first
+                reader.read_string();
+                reader.read_string().map_or(0, mpint_bits)
+            }
+            "ssh-dss" => reader.read_string().map_or(0, mpint_bits),
+            "ecdsa-sha2-nistp384" => 384,
+            "ecdsa-sha2-nistp521" => 521,
+            // Ed25519, NIST P-256 and their security key variants
+            _ => 256,
+        }
+    }
+
+    ///
+    /// Check if the key is backed by a FIDO security key (`sk-*` algorithms)
+    ///
+    pub fn is_security_key(&self) -> bool {
+        self.algorithm.starts_with("sk-")
+    }
+
+    ///
+    /// OpenSSH fingerprint of the key (like `ssh-keygen -l`)
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use superkeyloader_lib::ssh_key::SshPublicKey;
+    ///
+    /// let key = SshPublicKey::parse(
+    ///     "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg",
+    /// )
+    /// .unwrap();
+    ///
+    /// assert!(key.fingerprint().starts_with("SHA256:"));
+    /// assert!(key.md5_fingerprint().starts_with("MD5:"));
+    /// ```
+    ///
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(&self.blob);
+        format!(
+            "SHA256:{}",
+            base64::encode_config(digest, base64::STANDARD_NO_PAD)
+        )
+    }
+
+    ///
+    /// Legacy MD5 fingerprint of the key (like `ssh-keygen -l -E md5`)
+    ///
+    pub fn md5_fingerprint(&self) -> String {
+        let digest = Md5::digest(&self.blob);
+        let bytes: Vec<String> = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("MD5:{}", bytes.join(":"))
+    }
+
+    ///
+    /// Replace the key comment, an empty comment removes it
+    ///
+    /// # Errors
+    ///
+    /// Return `Error::InvalidKey` if the comment has control characters.
+    ///
+    pub fn with_comment(mut self, comment: Option<&str>) -> Result<SshPublicKey> {
+        let comment = comment.map(str::trim).filter(|comment| !comment.is_empty());
+        if let Some(comment) = comment {
+            check_text(comment)?;
+        }
+        self.comment = comment.map(String::from);
+        Ok(self)
+    }
+
+    ///
+    /// Replace `authorized_keys` options, validated by `KeyOptions::parse`
+    ///
+    pub fn with_options(mut self, options: Option<&KeyOptions>) -> SshPublicKey {
+        self.options = options.map(ToString::to_string);
+        self
+    }
+
+    ///
+    /// Same key, without `authorized_keys` options
+    ///
+    pub fn without_options(mut self) -> SshPublicKey {
+        self.options = None;
+        self
+    }
+
+    ///
+    /// Two keys are the same if algorithm and key data are equal
+    /// (options and comments are ignored)
+    ///
+    pub fn same_key(&self, other: &SshPublicKey) -> bool {
+        self.algorithm == other.algorithm && self.blob == other.blob
+    }
+}
+
+impl fmt::Display for SshPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(options) = &self.options {
+            write!(f, "{} ", options)?;
+        }
+        write!(f, "{} {}", self.algorithm, self.data)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+pub mod test_values {
+
+    pub const ED25519_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKJUbiqmkK8eaY3+g/1gZK4dWrWYfYOakv1m3sRVidak";
+
+    pub const RSA_2048_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf";
+
+    pub const ECDSA_256_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBFQC5MlQLEgyrVklawnRPtmBumdOF7oIJMGFL/ZKxmmJtdNv5bPgTHHADvXvTRrAiJQpjr/cc02E+Uf5J8N5TeE=";
+
+    pub const ECDSA_384_KEY: &str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBEs4nM0CQ65D1hB+jfKJdRKJaDhYeb9nTckUrzGVGmbmw3gZFHs/ZuwzLGVmiSQLTFttfTcjWDrRvxidrJT6xrHtyHAMlFaTH9rWRBsPMBpr6vLLVdqfLIUeTwlM/iVVfQ==";
+
+    pub const RSA_1024_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQCt8zka/7AVqjcet8Cc+UKwzRA/YGnYOA54FMkRItto7XrLTi4XgdZ+/lmqye1Qmsh6KX4PpUvIaxudWXNK9N1jcbRo6BltrXwpuXZ1zhdNrkmXxHdKX4jYBv6vpZ0Cgkf/ex9e6eP534bbh8aZR9msoXKr4Mc+Yyjb6k8hZszs9w==";
+
+    pub const SK_ED25519_KEY: &str = "sk-ssh-ed25519@openssh.com AAAAGnNrLXNzaC1lZDI1NTE5QG9wZW5zc2guY29tAAAAIAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gAAAABHNzaDo=";
+
+    pub const DSA_KEY: &str = "ssh-dss AAAAB3NzaC1kc3MAAACBANG4MBX0aL5oY0y0WXYX282qYAORyI2/7ARrvIofTpyFLloY5iGeDO71Y20Sya3D/8KhtwW8UwdxHohqFlGisQCNgZm+3yHmMr+seI21xm+naC2IBffuRKwlPKWEVOHOrGRIxXtC3TZsfOtO8dMEPGIYEh6NfyCrOai/82t5u8eNAAAAFQCnSucpOme7dsi8kKeSwSlqsB393QAAAIEAs1YiZTE/vPAmjQNluyx5B5QHbs4k0tiV+GQ92VbllCTlY4wb8jLqBj7Y9kx86Cd4I1Zzmsy7NEAnZwHVuXFzFzCqHx6aUqMPzlhZIMEWBkN/djj2AJZ0neX3dGrdrdeP+wTWQzMbYDK+btHNBlh4W4fw6T7ZjM5S1q35x8qfcigAAACBAJX6qMk+dkVgcBMES37he+TZAlIyvoJCpd5kDVD29fpKCzdXbV16pUFeAVLRCp0zzaYvy4wY6MSvZiJ3Q6mnJp+FqX62bL54e9/1ngJp5xIZzu6rqYY/vGBU3ZPDN0E4/OlwSe3hMeOmD4yZzf8Cog4cw2+Y/qjb5PtgSLL6evxl";
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_values::*;
+    use super::SshPublicKey;
+    use crate::error::Error;
+
+    #[test]
+    fn valid_keys() {
+        for key in &[
+            ED25519_KEY,
+            RSA_2048_KEY,
+            ECDSA_256_KEY,
+            ECDSA_384_KEY,
+            RSA_1024_KEY,
+            SK_ED25519_KEY,
+            DSA_KEY,
+        ] {
+            let parsed = SshPublicKey::parse(key).unwrap();
+            assert_eq!(&parsed.to_string(), key);
+            assert!(parsed.options().is_none());
+            assert!(parsed.comment().is_none());
+        }
+    }
+
+    #[test]
+    fn options_and_comment() {
+        let line = format!(
+            r#"from="10.0.0.1",command="echo \"a b\"" {} me@laptop"#,
+            ED25519_KEY
+        );
+        let key = SshPublicKey::parse(&line).unwrap();
+
+        assert_eq!(
+            key.options(),
+            Some(r#"from="10.0.0.1",command="echo \"a b\"""#)
+        );
+        assert_eq!(key.comment(), Some("me@laptop"));
+        assert_eq!(key.to_string(), line);
+
+        let key = key
+            .without_options()
+            .with_comment(Some("from-GH-id-1"))
+            .unwrap();
+        assert_eq!(key.to_string(), format!("{} from-GH-id-1", ED25519_KEY));
+        assert!(key.same_key(&SshPublicKey::parse(ED25519_KEY).unwrap()));
+        assert!(matches!(
+            key.with_comment(Some("x\ncommand=\"/bin/sh\"")),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn key_sizes() {
+        let bits = |line| SshPublicKey::parse(line).unwrap().bits();
+
+        assert_eq!(bits(ED25519_KEY), 256);
+        assert_eq!(bits(RSA_2048_KEY), 2048);
+        assert_eq!(bits(RSA_1024_KEY), 1024);
+        assert_eq!(bits(ECDSA_256_KEY), 256);
+        assert_eq!(bits(ECDSA_384_KEY), 384);
+        assert_eq!(bits(DSA_KEY), 1024);
+        assert!(SshPublicKey::parse(SK_ED25519_KEY)
+            .unwrap()
+            .is_security_key());
+        assert!(!SshPublicKey::parse(ED25519_KEY).unwrap().is_security_key());
+    }
+
+    #[test]
+    fn fingerprints() {
+        // Same as 'ssh-keygen -l' (and '-E md5') output
+        let ed25519 = SshPublicKey::parse(ED25519_KEY).unwrap();
+        assert_eq!(
+            ed25519.fingerprint(),
+            "SHA256:cBq2x3tjithe8kZ9GVQAK2/OiQmooY1DPIy8HjR0Db4"
+        );
+        assert_eq!(
+            ed25519.md5_fingerprint(),
+            "MD5:04:24:14:d8:b4:b3:74:80:39:f4:1b:e7:83:43:21:5b"
+        );
+
+        let rsa = SshPublicKey::parse(RSA_2048_KEY).unwrap();
+        assert_eq!(
+            rsa.fingerprint(),
+            "SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA"
+        );
+        assert_eq!(
+            rsa.md5_fingerprint(),
+            "MD5:d2:94:24:ae:e8:fc:e2:92:4c:b9:4d:7c:c2:b1:d2:b5"
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let invalid = |line: &str| matches!(SshPublicKey::parse(line), Err(Error::InvalidKey(_)));
+
+        assert!(invalid(""));
+        assert!(invalid("<html>Not found</html>"));
+        assert!(invalid("ssh-ed25519"));
+        assert!(invalid("ssh-foo AAAAC3NzaC1lZDI1NTE5"));
+        assert!(invalid("ssh-ed25519 not-base64!"));
+        // Injected line
+        assert!(invalid(&format!(
+            "{} me\ncommand=\"/bin/sh\" {}",
+            ED25519_KEY, ED25519_KEY
+        )));
+        // Algorithm mismatch
+        assert!(invalid(&ED25519_KEY.replace("ssh-ed25519", "ssh-rsa")));
+        // Truncated and extended key data
+        assert!(invalid(&RSA_2048_KEY[..RSA_2048_KEY.len() - 8]));
+        assert!(invalid(&format!("{}AAAA", ED25519_KEY)));
+    }
+}
@@ -5,28 +5,28 @@ pub extern crate log;
 mod github_integration_test {
 
     const CLI_BIN: &str = "superkeyloader"; // Binary file name
-    const VALID_USERNAME: &str = "biosan"; // My own username
-    const VALID_USERNAME_KEYS: usize = 3; // I currently have 3 SSH keys TODO: Use a more robust solution
+    const VALID_USERNAME_KEYS: usize = 3; // Keys in 'VALID_3_KEYS_JSON' fixture
+    const BLOCK_MARKERS: usize = 2; // Managed block BEGIN and END lines
     const INVALID_USERNAME: &str = "test-"; // It ends with a hyphen
-    const MISSING_USERNAME: &str = "about"; // It's a reserved username so it will never be used
-    const GITHUB_TOKEN: &str = "GITHUB_TOKEN";
 
     use assert_cmd::Command;
+    use mockito::{mock, Matcher, Mock};
     use predicates::prelude::*; // Used for writing assertions
-    use rand::Rng;
     use std::env;
     use std::fs::File;
     use std::io::{BufRead, BufReader, Write};
     use std::path::PathBuf;
-    // NOTE: Switched to a random file into OS's temporary directory.
-    //use tempfile::NamedTempFile;
+    use superkeyloader_lib::github::test_values::*;
+    use superkeyloader_lib::ssh_key::test_values::{
+        ECDSA_384_KEY, ED25519_KEY, RSA_1024_KEY, SK_ED25519_KEY,
+    };
+    use superkeyloader_lib::ssh_key::SshPublicKey;
+    use tempfile::TempDir;
 
     #[test]
     fn invalid_username() -> Result<(), Box<dyn std::error::Error>> {
         init();
-        let mut cmd = Command::cargo_bin(CLI_BIN)?;
-        cmd = _add_api_token(cmd); // Set to token to raise API rate limit
-        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
+        let mut cmd = _command()?;
         cmd.arg(INVALID_USERNAME);
         cmd.assert()
             .failure()
@@ -37,9 +37,9 @@ mod github_integration_test {
     #[test]
     fn missing_username() -> Result<(), Box<dyn std::error::Error>> {
         init();
-        let mut cmd = Command::cargo_bin(CLI_BIN)?;
-        cmd = _add_api_token(cmd); // Set to token to raise API rate limit
-        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
+        let _m = _mock_keys(MISSING_USERNAME, 404, r#"{"message": "Not Found"}"#);
+
+        let mut cmd = _command()?;
         cmd.arg(MISSING_USERNAME);
         cmd.assert()
             .failure()
@@ -50,24 +50,29 @@ mod github_integration_test {
     #[test]
     fn valid_user_create_file() -> Result<(), Box<dyn std::error::Error>> {
         init();
-        let file_path = _create_test_file(0);
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
 
-        let mut cmd = Command::cargo_bin(CLI_BIN)?;
+        let mut cmd = _command()?;
         cmd.arg("--human"); // Force human parsable output
         cmd.arg("--output"); // Write keys into file './test'
         cmd.arg(&file_path);
-        cmd = _add_api_token(cmd); // Set to token to raise API rate limit
-        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
         cmd.arg(VALID_USERNAME);
         cmd.assert().success().stdout(
             predicate::str::contains("Downloaded")
                 .and(predicates::str::contains("SSH keys"))
-                .and(predicates::str::contains(VALID_USERNAME)),
+                .and(predicates::str::contains(VALID_USERNAME))
+                .and(predicates::str::contains(
+                    "  SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA from-GH-id-12257919 (ssh-rsa)",
+                )),
         ); // TODO: Case insensitive match
 
         let lines = _read_test_file(&file_path);
 
-        assert_eq!(lines.len(), VALID_USERNAME_KEYS);
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert_eq!(lines[0], "# BEGIN superkeyloader gh:testuser");
+        assert!(lines[1].ends_with(" from-GH-id-12257919"));
+        assert_eq!(lines[4], "# END superkeyloader gh:testuser");
 
         Ok(())
     }
@@ -75,50 +80,1060 @@ mod github_integration_test {
     #[test]
     fn valid_user_append_file() -> Result<(), Box<dyn std::error::Error>> {
         init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
         let exising_lines: usize = 3;
 
         // Create test file with 3 lines
-        let file_path = _create_test_file(3);
+        let (_home, file_path) = _create_test_file(3);
 
-        let mut cmd = Command::cargo_bin(CLI_BIN)?;
+        let mut cmd = _command()?;
         cmd.arg("--human"); // Force human parsable output
         cmd.arg("--output"); // Write keys into file './test'
         cmd.arg(&file_path);
-        cmd = _add_api_token(cmd); // Set to token to raise API rate limit
-        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
         cmd.arg(VALID_USERNAME);
         cmd.assert().success().stdout(
             predicate::str::contains("Downloaded")
                 .and(predicates::str::contains("SSH keys"))
-                .and(predicates::str::contains(VALID_USERNAME)),
+                .and(predicates::str::contains(VALID_USERNAME))
+                .and(predicates::str::contains(
+                    "  SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA from-GH-id-12257919 (ssh-rsa)",
+                )),
         ); // TODO: Case insensitive match
 
         let lines = _read_test_file(&file_path);
 
-        assert_eq!(lines.len(), exising_lines + VALID_USERNAME_KEYS);
+        assert_eq!(
+            lines.len(),
+            exising_lines + VALID_USERNAME_KEYS + BLOCK_MARKERS
+        );
+        assert_eq!(lines[0], "helloooo");
 
         Ok(())
     }
 
     #[test]
-    fn valid_user_json_output() -> Result<(), Box<dyn std::error::Error>> {
+    fn valid_user_run_twice() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        for added in &[VALID_USERNAME_KEYS, 0] {
+            let mut cmd = _command()?;
+            cmd.arg("--human"); // Force human parsable output
+            cmd.arg("--output");
+            cmd.arg(&file_path);
+            cmd.arg(VALID_USERNAME);
+            cmd.assert()
+                .success()
+                .stdout(predicate::str::contains(format!(
+                    "Appended {} SSH keys to 'authorized_keys' file ({} already present)",
+                    added,
+                    VALID_USERNAME_KEYS - added
+                )));
+        }
+
+        assert_eq!(
+            _read_test_file(&file_path).len(),
+            VALID_USERNAME_KEYS + BLOCK_MARKERS
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_already_present() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+        // Same key of the first fixture, with options and a different comment
+        std::fs::write(
+            &file_path,
+            "no-pty ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf laptop",
+        )?;
+
+        let mut cmd = _command()?;
+        cmd.arg("--json"); // Force JSON output
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success().stdout(
+            predicate::str::contains("\"added\":2").and(predicate::str::contains("\"present\":1")),
+        );
+
+        let lines = _read_test_file(&file_path);
+
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(lines[0].ends_with(" laptop"));
+        assert!(lines[2].ends_with(" from-GH-id-22932337"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_migrates_legacy_keys() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+        std::fs::write(
+            &file_path,
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg me\n\
+             ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDjyfn1cwRgSsYUGqkMeIn4I5qn4pmQr30/8lcnwneuxJHfBnX/AJgv9VCAyDzKhZ1jGj/p7LB5/tiyXPu0+SFzmilpj+mhhKC342fXn158GsWRYl0HYbgLC+zjJ+21g9a69UpJOqanWuXZRijyJAYodvywaQQ422xkYsG1EcAoDtIdBhlx49afRmh6itcZ0N0wFQSbQSShcQQhBn5NxMY7v4gLzepU2WF1EhuA7J0hmwNQJjHJwexNB4GJQ9TBOtwWKriQLSEIsh6X1lYaa4XePGYh+qkNABIBacI2gSZovB5W4ItZAQSs/pTTOjVzUA6VqeW+liHwN9rs7vbWB5Hf from-GH-id-12257919\n\
+             ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDdeleted from-GH-id-1\n\
+             ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDgitlab from-GL-id-2\n",
+        )?;
+
+        let mut cmd = _command()?;
+        cmd.arg("--human"); // Force human parsable output
+        cmd.arg("--sync");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success().stdout(
+            predicate::str::contains(
+                "Synced 'authorized_keys' file: 2 SSH keys added, 0 removed, 1 kept.",
+            )
+            .and(predicate::str::contains(
+                "1 SSH keys written by an older version moved into their managed block.",
+            ))
+            .and(predicate::str::contains(
+                "Warning: 1 SSH keys written by an older version were left in place",
+            )),
+        );
+
+        let lines = _read_test_file(&file_path);
+
+        // The legacy key still upstream is moved into the block of the synced user, the one gone
+        // upstream can't be tied to the synced user (GitHub tags are shared by every user) and is
+        // left where it is
+        assert_eq!(lines.len(), 6 + BLOCK_MARKERS);
+        assert!(lines[0].ends_with(" me"));
+        assert!(lines[1].ends_with("from-GH-id-1"));
+        assert!(lines[2].ends_with("from-GL-id-2"));
+        assert_eq!(lines[3], "# BEGIN superkeyloader gh:testuser");
+        assert!(lines[4].ends_with("from-GH-id-12257919"));
+
+        // Nothing left to move, the unattributed key is reported again
+        let mut cmd = _command()?;
+        cmd.arg("--json");
+        cmd.arg("--sync");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"moved\":0"))
+            .stdout(predicate::str::contains(
+                "\"unattributed\":[{\"fingerprint\":\"no valid key\",\"key\":\"ssh-rsa \
+                 AAAAB3NzaC1yc2EAAAADAQABAAABAQDdeleted from-GH-id-1\"}]",
+            ));
+        assert_eq!(_read_test_file(&file_path), lines);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_keeps_keys_on_errors() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let _e = _mock_keys(MISSING_USERNAME, 500, "");
+        let (_home, file_path) = _create_test_file(0);
+        std::fs::write(
+            &file_path,
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDerruser from-GH-id-1\n",
+        )?;
+
+        let mut cmd = _command()?;
+        cmd.arg("--json"); // Force JSON output
+        cmd.arg("--sync");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.arg(MISSING_USERNAME);
+        cmd.assert()
+            .failure()
+            .stdout(predicate::str::contains("\"removed\":0"));
+
+        let lines = _read_test_file(&file_path);
+
+        assert_eq!(lines.len(), 1 + VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(lines[0].ends_with("from-GH-id-1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn managed_block_rewrite() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+        std::fs::write(
+            &file_path,
+            "# Added by cloud-init\n\
+             # BEGIN superkeyloader gh:testuser\n\
+             ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDdeleted from-GH-id-1\n\
+             # END superkeyloader gh:testuser\n\
+             ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDansible ansible\n",
+        )?;
+
+        let mut cmd = _command()?;
+        cmd.arg("--json"); // Force JSON output
+        cmd.arg("--sync");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(format!("github:{}", VALID_USERNAME));
+        cmd.assert().success().stdout(
+            predicate::str::contains("\"added\":3").and(predicate::str::contains("\"removed\":1")),
+        );
+
+        let lines = _read_test_file(&file_path);
+
+        assert_eq!(lines.len(), 2 + VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert_eq!(lines[0], "# Added by cloud-init");
+        assert_eq!(lines[1], "# BEGIN superkeyloader gh:testuser");
+        assert!(lines[2].ends_with("from-GH-id-12257919"));
+        assert_eq!(lines[5], "# END superkeyloader gh:testuser");
+        assert!(lines[6].ends_with(" ansible"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_ssh_directory() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (home, _) = _create_test_file(0);
+        let file_path = home.path().join(".ssh").join("authorized_keys");
+
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+
+        assert_eq!(
+            _read_test_file(&file_path).len(),
+            VALID_USERNAME_KEYS + BLOCK_MARKERS
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn insecure_permissions() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o666))?;
+
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("writable by group or others"));
+
+        assert_eq!(_read_test_file(&file_path), vec!["helloooo"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+
+        let mut cmd = _command()?;
+        cmd.args(["--dry-run", "--human", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "@@ -1 +1,6 @@\n helloooo\n+# BEGIN",
+            ))
+            .stdout(predicate::str::contains("3 SSH keys would be added"));
+
+        let mut cmd = _command()?;
+        cmd.args(["--dry-run", "--json", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"dry_run\":true"))
+            .stdout(predicate::str::contains(
+                "{\"action\":\"add\",\"line\":\"ssh-",
+            ));
+
+        // Nothing written and no backups
+        assert_eq!(_read_test_file(&file_path), vec!["helloooo"]);
+        assert_eq!(std::fs::read_dir(file_path.parent().unwrap())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stdout_output() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+
+        let mut cmd = _command()?;
+        cmd.args(["--stdout", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let lines: Vec<&str> = std::str::from_utf8(&output)?.lines().collect();
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS);
+        assert!(lines.iter().all(|line| SshPublicKey::parse(line).is_ok()));
+
+        // File is not touched
+        assert_eq!(_read_test_file(&file_path), vec!["helloooo"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_policy() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        // Default policy
+        let mut cmd = _command()?;
+        cmd.env_remove("SUPERKEYLOADER_MIN_RSA_BITS");
+        cmd.args(["--human", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success().stdout(predicate::str::contains(
+            "  Rejected SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA from-GH-id-12257919 \
+             (ssh-rsa): Key not allowed by policy, RSA key has 2048 bits, at least 3072 are required",
+        ));
+        assert_eq!(_read_test_file(&file_path).len(), 2 + BLOCK_MARKERS);
+
+        // Policy from environment, rejected keys removed by sync
+        let mut cmd = _command()?;
+        cmd.env("SUPERKEYLOADER_ALLOWED_ALGORITHMS", "ssh-rsa,ssh-dss");
+        cmd.args(["--json", "--sync", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "\"reason\":\"Key not allowed by policy",
+            ))
+            .stdout(predicate::str::contains("\"removed\":2"));
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), 1 + BLOCK_MARKERS);
+        assert!(lines[1].starts_with("ssh-rsa "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_options() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        let mut cmd = _command()?;
+        cmd.args(["--human", "--key-options", r#"restrict,from="10.0.0.0/8""#]);
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(lines[1].starts_with(r#"restrict,from="10.0.0.0/8" ssh-rsa "#));
+
+        // Identity options replace global ones and rewrite keys already present
+        let mut cmd = _command()?;
+        cmd.args(["--human", "--key-options", "restrict"]);
+        cmd.args(["--identity-options", VALID_USERNAME, "no-pty"]);
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success().stdout(predicate::str::contains(
+            "3 SSH keys already present rewritten with new options.",
+        ));
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(lines[1].starts_with("no-pty ssh-rsa "));
+
+        // Invalid options never reach the file
+        let mut cmd = _command()?;
+        cmd.args(["--key-options", r#"restrict,from="10.0.0.0/33""#]);
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Invalid 'authorized_keys' options: invalid value \"10.0.0.0/33\" for option 'from'",
+        ));
+
+        let mut cmd = _command()?;
+        cmd.args(["--identity-options", "gh:bob", "no-pty"]);
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("isn't one of the identities"));
+        assert_eq!(_read_test_file(&file_path), lines);
+
+        Ok(())
+    }
+
+    #[test]
+    fn time_limited_grant() -> Result<(), Box<dyn std::error::Error>> {
         init();
-        let file_path = _create_test_file(0);
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+
+        let mut cmd = _command()?;
+        cmd.args([
+            "--json",
+            "--key-options",
+            "restrict",
+            "--for",
+            "8h",
+            "--output",
+        ]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"grant_until\":\"20"));
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), 1 + VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(lines[2].starts_with("restrict,expiry-time=\"20"));
+        assert!(lines[2].contains(" grant-until-20"));
+        assert!(lines[2].ends_with(" from-GH-id-12257919"));
+
+        // Nothing expired yet
+        let mut cmd = _command()?;
+        cmd.args(["--human", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg("prune");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Pruned 0 expired SSH keys"));
+        assert_eq!(_read_test_file(&file_path), lines);
+
+        // Expire one key by hand
+        let mut expired = lines.clone();
+        let grant = expired[2].find("grant-until-").unwrap() + "grant-until-".len();
+        expired[2].replace_range(grant..grant + 16, "20200401T000000Z");
+        std::fs::write(&file_path, format!("{}\n", expired.join("\n")))?;
+
+        let mut cmd = _command()?;
+        cmd.args(["--human", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg("prune");
+        cmd.assert().success().stdout(predicate::str::contains(
+            "Pruned 1 expired SSH keys from 'authorized_keys' file.\n  \
+             SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA \
+             grant-until-20200401T000000Z from-GH-id-12257919 (ssh-rsa)",
+        ));
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + BLOCK_MARKERS);
+        assert!(!lines.iter().any(|line| line.contains("12257919")));
+
+        let mut cmd = _command()?;
+        cmd.args(["--for", "8hours", VALID_USERNAME]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Invalid duration '8hours'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn revoke_identity() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), 1 + VALID_USERNAME_KEYS + BLOCK_MARKERS);
+
+        let mut cmd = _command()?;
+        cmd.args(["--human", "--output"]);
+        cmd.arg(&file_path);
+        cmd.args(["revoke", "--dry-run", "gh:testuser"]);
+        cmd.assert().success().stdout(
+            predicate::str::contains("Would revoke 3 SSH keys of 'gh:testuser'.")
+                .and(predicate::str::contains(
+                    "  SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA from-GH-id-12257919 (ssh-rsa)",
+                ))
+                .and(predicate::str::contains("Dry run")),
+        );
+        assert_eq!(_read_test_file(&file_path), lines);
+
+        let mut cmd = _command()?;
+        cmd.args(["--json", "--output"]);
+        cmd.arg(&file_path);
+        cmd.args(["revoke", VALID_USERNAME]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"identity\":\"testuser\"").and(
+                predicate::str::contains(
+                    "\"fingerprint\":\"SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA\"",
+                ),
+            ));
+        assert_eq!(_read_test_file(&file_path), vec!["helloooo"]);
+
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.args(["revoke", "nope:alice"]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Unknown provider 'nope'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_keys() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        let mut cmd = _command()?;
+        cmd.args(["--key-options", "no-pty", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+
+        let mut cmd = _command()?;
+        cmd.args(["--human", "list", "--output"]);
+        cmd.arg(&file_path);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with("gh:testuser (3 keys)\n").and(
+                predicate::str::contains(
+                    "  2048 SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA \
+                 from-GH-id-12257919 (ssh-rsa) [no-pty]\n",
+                ),
+            ));
+
+        let mut cmd = _command()?;
+        cmd.args(["--json", "list", "--output"]);
+        cmd.arg(&file_path);
+        cmd.assert().success().stdout(
+            predicate::str::contains("\"origin\":\"gh:testuser\"")
+                .and(predicate::str::contains("\"managed\":true"))
+                .and(predicate::str::contains("\"bits\":2048"))
+                .and(predicate::str::contains("\"options\":\"no-pty\"")),
+        );
+
+        let (_junk_home, junk_path) = _create_test_file(2);
+        let mut cmd = _command()?;
+        cmd.args(["--human", "list", "--output"]);
+        cmd.arg(&junk_path);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("No SSH keys"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_drift() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        let check = |file_path: &PathBuf| -> Result<_, Box<dyn std::error::Error>> {
+            let mut cmd = _command()?;
+            cmd.args(["--human", "--output"]);
+            cmd.arg(file_path);
+            cmd.args(["check", VALID_USERNAME]);
+            Ok(cmd.assert())
+        };
+
+        // Nothing imported yet
+        check(&file_path)?
+            .code(2)
+            .stdout(predicate::str::contains("Missing SSH keys (3):"));
+
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+
+        check(&file_path)?
+            .success()
+            .stdout(predicate::str::contains("is in sync with 1 identities"));
+
+        // Hand-written key, a managed key gone upstream and a legacy tagged key (maybe someone
+        // else's, it is only reported)
+        let mut drifted = _read_test_file(&file_path);
+        drifted.insert(0, format!("{} me@laptop", ECDSA_384_KEY));
+        drifted.insert(2, format!("{} from-GH-id-1", RSA_1024_KEY));
+        drifted.push(format!("{} from-GH-id-2", SK_ED25519_KEY));
+        std::fs::write(&file_path, format!("{}\n", drifted.join("\n")))?;
+
+        check(&file_path)?.code(12).stdout(
+            predicate::str::contains("Unmanaged SSH keys (1):")
+                .and(predicate::str::contains("Stale or outdated SSH keys (1):"))
+                .and(predicate::str::contains(
+                    "Unattributed tagged SSH keys (1):",
+                )),
+        );
+        assert_eq!(_read_test_file(&file_path), drifted);
+
+        let mut cmd = _command()?;
+        cmd.args(["--json", "--output"]);
+        cmd.arg(&file_path);
+        cmd.args(["check", VALID_USERNAME]);
+        cmd.assert()
+            .code(12)
+            .stdout(predicate::str::contains("\"in_sync\":false"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_and_restore() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+        let backups = |file_path: &PathBuf| {
+            let directory = file_path.parent().unwrap();
+            std::fs::read_dir(directory).unwrap().count() - 1
+        };
+
+        // New keys, first backup
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+        assert_eq!(backups(&file_path), 1);
+
+        // Nothing changed, no backup
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+        assert_eq!(backups(&file_path), 1);
 
         let mut cmd = Command::cargo_bin(CLI_BIN)?;
-        cmd.arg("--json"); // Force human parsable output
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.args(["restore", "--list", "--human"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("authorized_keys.superkeyloader-"));
+
+        // Restore the latest backup (and backup the current file)
+        let mut cmd = Command::cargo_bin(CLI_BIN)?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.args(["restore", "--json"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"restored\":"));
+        assert_eq!(_read_test_file(&file_path), vec!["helloooo"]);
+        assert_eq!(backups(&file_path), 2);
+
+        let mut cmd = Command::cargo_bin(CLI_BIN)?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.args(["restore", "missing.bak"]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Backup 'missing.bak' not found"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_retention() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(1);
+
+        for _ in 0..3 {
+            let mut cmd = _command()?;
+            cmd.args(["--sync", "--backups", "2", "--output"]);
+            cmd.arg(&file_path);
+            cmd.arg(VALID_USERNAME);
+            cmd.assert().success();
+
+            // Force a change on every run
+            std::fs::write(&file_path, "helloooo\n")?;
+        }
+
+        let directory = file_path.parent().unwrap();
+        assert_eq!(std::fs::read_dir(directory)?.count(), 1 + 2);
+
+        // Backups disabled
+        let mut cmd = _command()?;
+        cmd.args(["--backups", "0", "--output"]);
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success();
+        assert_eq!(std::fs::read_dir(directory)?.count(), 1 + 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn valid_user_json_output() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let (_home, file_path) = _create_test_file(0);
+
+        let mut cmd = _command()?;
+        cmd.arg("--json"); // Force JSON output
         cmd.arg("--output"); // Write keys into file './test'
-        cmd.arg(file_path);
-        cmd = _add_api_token(cmd); // Set to token to raise API rate limit
-        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
+        cmd.arg(&file_path);
         cmd.arg(VALID_USERNAME);
         cmd.assert()
             .success()
-            .stdout(predicate::str::contains("{\"keys\":[\"ssh-"));
+            .stdout(predicate::str::contains("\"key\":\"ssh-"))
+            .stdout(predicate::str::contains(
+                "\"fingerprint\":\"SHA256:NX3VprImYtDmhtDUmbSJhHnp3FkUEwUB+KLGc3UgTjA\"",
+            ))
+            .stdout(predicate::str::contains("\"md5\":").not());
+
+        // Legacy MD5 fingerprints on request
+        let (_md5_home, md5_path) = _create_test_file(0);
+        let mut cmd = _command()?;
+        cmd.args(["--json", "--md5", "--output"]);
+        cmd.arg(&md5_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert().success().stdout(predicate::str::contains(
+            "\"md5\":\"MD5:d2:94:24:ae:e8:fc:e2:92:4c:b9:4d:7c:c2:b1:d2:b5\"",
+        ));
 
         Ok(())
     }
 
+    #[test]
+    fn json_output_with_errors() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(MISSING_USERNAME, 404, r#"{"message": "Not Found"}"#);
+        let (_home, file_path) = _create_test_file(0);
+
+        let mut cmd = _command()?;
+        cmd.arg("--json"); // Force JSON output
+        cmd.arg("--output"); // Write keys into file './test'
+        cmd.arg(&file_path);
+        cmd.arg(MISSING_USERNAME);
+        cmd.assert()
+            .failure()
+            .stdout(predicate::str::contains("\"keys\":[]"))
+            .stdout(predicate::str::contains("\"error\":\"Wrong username"));
+
+        assert!(_read_test_file(&file_path).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_keys() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, EMPTY_JSON);
+
+        let (_home, file_path) = _create_test_file(0);
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("no SSH keys"));
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_sent_to_their_provider_only() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m1 = mock("GET", "/users/testuser/keys")
+            .match_header("Authorization", "token github-secret")
+            .with_status(200)
+            .with_body(VALID_3_KEYS_JSON)
+            .create();
+        let _m2 = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(Matcher::Any)
+            .match_header("Authorization", Matcher::Missing)
+            .match_header("PRIVATE-TOKEN", Matcher::Missing)
+            .with_status(200)
+            .with_body(superkeyloader_lib::gitea::test_values::VALID_2_KEYS_JSON)
+            .expect(1)
+            .create();
+
+        let (_home, file_path) = _create_test_file(0);
+        let mut cmd = _command()?;
+        cmd.arg("--gitea-url");
+        cmd.arg(mockito::server_url());
+        cmd.arg("--github-token");
+        cmd.arg("github-secret");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg("gh:testuser");
+        cmd.arg("gt:test.user");
+        cmd.assert().success();
+        _m2.assert();
+
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_prefixes() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _gh = _mock_keys(VALID_USERNAME, 200, VALID_3_KEYS_JSON);
+        let _gl = mock("GET", "/api/v4/users/test.user/keys")
+            .with_status(200)
+            .with_body(_keys_json(1121029, ECDSA_384_KEY))
+            .create();
+        let _gt = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body(_keys_json(123, SK_ED25519_KEY))
+            .create();
+        let _url = mock("GET", "/carol.keys")
+            .with_status(200)
+            .with_body(superkeyloader_lib::plain_keys::test_values::VALID_2_KEYS_TEXT)
+            .create();
+        let url = format!("{}/carol.keys", mockito::server_url());
+        let host = mockito::server_url().replace("http://", "");
+
+        let (_home, file_path) = _create_test_file(0);
+        let mut cmd = _providers_command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME); // Default provider
+        cmd.arg("gl:test.user");
+        cmd.arg("gt:test.user");
+        cmd.arg(&url);
+        cmd.assert().success();
+
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), VALID_USERNAME_KEYS + 4 + 4 * BLOCK_MARKERS);
+        let block = |name: &str| -> Vec<String> {
+            let begin = format!("# BEGIN superkeyloader {}", name);
+            let end = format!("# END superkeyloader {}", name);
+            lines
+                .iter()
+                .skip_while(|line| **line != begin)
+                .skip(1)
+                .take_while(|line| **line != end)
+                .cloned()
+                .collect()
+        };
+        assert_eq!(block("gh:testuser").len(), VALID_USERNAME_KEYS);
+        assert_eq!(
+            block("gl:test.user"),
+            vec![format!("{} from-GL-id-1121029", ECDSA_384_KEY)]
+        );
+        assert_eq!(
+            block("gt:test.user"),
+            vec![format!("{} from-{}-id-123", SK_ED25519_KEY, host)]
+        );
+        let url_keys = block(&url);
+        assert_eq!(url_keys.len(), 2);
+        assert_eq!(
+            url_keys[0],
+            format!(
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg \
+                 from-{}-id-SHA256:NnuZc2c1W5aferYtlz4ynnUN/trloSXHkOfqm4wn+Zc",
+                url
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_removes_deleted_keys() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _gl = mock("GET", "/api/v4/users/test.user/keys")
+            .with_status(200)
+            .with_body(_keys_json(1121029, ECDSA_384_KEY))
+            .create();
+        let _gt = mock("GET", "/api/v1/users/test.user/keys")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body("[]")
+            .create();
+        let _url = mock("GET", "/carol.keys")
+            .with_status(200)
+            .with_body(superkeyloader_lib::plain_keys::test_values::VALID_2_KEYS_TEXT)
+            .create();
+        let url = format!("{}/carol.keys", mockito::server_url());
+
+        // Keys deleted upstream: one in the GitLab block, the whole Gitea block, and a legacy
+        // key of the URL (its tag names the identity). The legacy GitHub key can't be tied to
+        // anyone and is kept.
+        let (_home, file_path) = _create_test_file(0);
+        std::fs::write(
+            &file_path,
+            format!(
+                "{hand} me@laptop\n\
+                 # BEGIN superkeyloader gl:test.user\n\
+                 {ecdsa} from-GL-id-1121029\n\
+                 {sk} from-GL-id-1121030\n\
+                 # END superkeyloader gl:test.user\n\
+                 # BEGIN superkeyloader gt:test.user\n\
+                 {sk} from-{host}-id-123\n\
+                 # END superkeyloader gt:test.user\n\
+                 {sk} from-{url}-id-SHA256:deleted\n\
+                 {rsa} from-GH-id-1\n",
+                hand = ED25519_KEY,
+                ecdsa = ECDSA_384_KEY,
+                sk = SK_ED25519_KEY,
+                rsa = RSA_1024_KEY,
+                host = mockito::server_url().replace("http://", ""),
+                url = url
+            ),
+        )?;
+
+        let mut cmd = _providers_command()?;
+        cmd.arg("--human");
+        cmd.arg("--sync");
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg("gl:test.user");
+        cmd.arg("gt:test.user");
+        cmd.arg(&url);
+        // Gitea user has no keys left, that is reported as an error after syncing
+        cmd.assert()
+            .code(1)
+            .stdout(predicate::str::contains(
+                "Synced 'authorized_keys' file: 2 SSH keys added, 3 removed, 1 kept.",
+            ))
+            .stderr(predicate::str::contains(
+                "'gt:test.user': User has no SSH keys",
+            ));
+
+        let lines = _read_test_file(&file_path);
+        assert_eq!(
+            lines,
+            vec![
+                format!("{} me@laptop", ED25519_KEY),
+                "# BEGIN superkeyloader gl:test.user".to_string(),
+                format!("{} from-GL-id-1121029", ECDSA_384_KEY),
+                "# END superkeyloader gl:test.user".to_string(),
+                format!("{} from-GH-id-1", RSA_1024_KEY),
+                format!("# BEGIN superkeyloader {}", url),
+                format!(
+                    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE8q1ayLLqY5MZyVfgSL/L8hNkSh2jokNKhSips4iHZg \
+                     from-{}-id-SHA256:NnuZc2c1W5aferYtlz4ynnUN/trloSXHkOfqm4wn+Zc",
+                    url
+                ),
+                format!(
+                    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAefJue1kiGa+dbcbbd2WBO7EPPwFF0vFD8mFtIN3FHz \
+                     from-{}-id-{}",
+                    url,
+                    SshPublicKey::parse(
+                        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAefJue1kiGa+dbcbbd2WBO7EPPwFF0vFD8mFtIN3FHz"
+                    )?
+                    .fingerprint()
+                ),
+                format!("# END superkeyloader {}", url),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_urls_with_query() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _alice = mock("GET", "/keys?user=alice")
+            .with_status(200)
+            .with_body(format!("{}\n", ECDSA_384_KEY))
+            .create();
+        let _bob = mock("GET", "/keys?user=bob")
+            .with_status(200)
+            .with_body(format!("{}\n", SK_ED25519_KEY))
+            .create();
+        let alice = format!("{}/keys?user=alice", mockito::server_url());
+        let bob = format!(
+            "url:{}/keys?user=bob",
+            mockito::server_url().replace("http://", "http://bot:password@")
+        );
+
+        // Same public URL, but different identities: one block each, nothing removed
+        let (_home, file_path) = _create_test_file(0);
+        for _ in 0..2 {
+            let mut cmd = _providers_command()?;
+            cmd.arg("--human");
+            cmd.arg("--sync");
+            cmd.arg("--output");
+            cmd.arg(&file_path);
+            cmd.arg(&alice);
+            cmd.arg(&bob);
+            cmd.assert()
+                .success()
+                .stdout(predicate::str::contains(" 0 removed,"))
+                .stdout(predicate::str::contains("password").not())
+                .stdout(predicate::str::contains("user=").not());
+        }
+
+        let lines = _read_test_file(&file_path);
+        assert_eq!(lines.len(), 2 + 2 * BLOCK_MARKERS);
+        assert!(lines[1].starts_with(ECDSA_384_KEY));
+        assert!(lines[4].starts_with(SK_ED25519_KEY));
+        assert_ne!(lines[0], lines[3]);
+        for line in &lines {
+            assert!(!line.contains("password") && !line.contains("user="));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_response() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = _mock_keys(VALID_USERNAME, 200, INVALID_JSON);
+
+        let (_home, file_path) = _create_test_file(0);
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Invalid provider API response"));
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limited() -> Result<(), Box<dyn std::error::Error>> {
+        init();
+        let _m = mock("GET", "/users/testuser/keys")
+            .with_status(403)
+            .with_header("X-RateLimit-Remaining", "0")
+            .with_body(r#"{"message": "API rate limit exceeded"}"#)
+            .create();
+
+        let (_home, file_path) = _create_test_file(0);
+        let mut cmd = _command()?;
+        cmd.arg("--output");
+        cmd.arg(&file_path);
+        cmd.arg(VALID_USERNAME);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("API rate limit exceeded"));
+        Ok(())
+    }
+
     //
     // Utility functions
     //
@@ -129,16 +1144,58 @@ mod github_integration_test {
             .try_init();
     }
 
-    fn _create_test_file(lines: usize) -> std::path::PathBuf {
-        let postfix: u32 = rand::thread_rng().gen();
-        let tempdir = std::env::temp_dir();
-        let filename = PathBuf::from(format!("temp-{}", postfix));
-        let filepath = tempdir.join(filename);
+    // Binary pointed to the local mock server instead of the real GitHub API
+    fn _command() -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CLI_BIN)?;
+        cmd.arg("--github-api-url");
+        cmd.arg(mockito::server_url());
+        // Test keys include a 2048 bits RSA key, below the default policy
+        cmd.env("SUPERKEYLOADER_MIN_RSA_BITS", "2048");
+        cmd = _env_args(cmd); // Add additional arguments from 'RUST_TEST_ARGS' environment variable
+        Ok(cmd)
+    }
+
+    // Binary pointed to the local mock server for all the providers with a configurable URL
+    fn _providers_command() -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = _command()?;
+        cmd.arg("--gitlab-url");
+        cmd.arg(mockito::server_url());
+        cmd.arg("--gitea-url");
+        cmd.arg(mockito::server_url());
+        cmd.arg("--allow-http"); // Mock server has no TLS
+        Ok(cmd)
+    }
+
+    // GitLab and Gitea style JSON response with a single key
+    fn _keys_json(id: u64, key: &str) -> String {
+        format!(r#"[{{"id": {}, "title": "laptop", "key": "{}"}}]"#, id, key)
+    }
+
+    fn _mock_keys(username: &str, status: usize, body: &str) -> Mock {
+        mock("GET", format!("/users/{}/keys", username).as_str())
+            .with_status(status)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_body(body)
+            .create()
+    }
+
+    // NOTE: OS's temporary directory is world writable, so the file is created in a private
+    //       sub-directory (otherwise it would be rejected like sshd 'StrictModes' does). The
+    //       directory is deleted when the returned 'TempDir' is dropped, keep it until the end.
+    fn _create_test_file(lines: usize) -> (TempDir, PathBuf) {
+        let tempdir = TempDir::new().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let private = std::fs::Permissions::from_mode(0o700);
+            std::fs::set_permissions(tempdir.path(), private).unwrap();
+        }
+        let filepath = tempdir.path().join("authorized_keys");
         let mut file = File::create(&filepath).unwrap();
         for _ in 0..lines {
             writeln!(file, "helloooo").unwrap();
         }
-        filepath
+        (tempdir, filepath)
     }
 
     fn _read_test_file(path: &PathBuf) -> Vec<String> {
@@ -148,16 +1205,6 @@ mod github_integration_test {
         lines
     }
 
-    fn _add_api_token(mut cmd: assert_cmd::cmd::Command) -> assert_cmd::cmd::Command {
-        let token = env::var(GITHUB_TOKEN);
-        if let Ok(gh_token) = token {
-            warn!("Got a GitHub token!");
-            cmd.arg("--token");
-            cmd.arg(gh_token);
-        }
-        cmd
-    }
-
     // TODO: Maybe use 'RUST_LOG' env var to set only log level
     // NOTE: Log output should be written on file to not interfer with STDOUT/STDERR
     fn _env_args(mut cmd: assert_cmd::cmd::Command) -> assert_cmd::cmd::Command {